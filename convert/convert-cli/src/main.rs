@@ -1,21 +1,541 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::path::PathBuf;
-use clap::Parser;
-use eink_convert::convert;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use glob::{MatchOptions, Pattern};
+use image::ImageError;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use walkdir::WalkDir;
+use image::imageops::{resize, FilterType};
+use eink_convert::color::display_color::DisplayColor;
+use eink_convert::adjust::Adjustments;
+use eink_convert::calibration::{calibration_chart, calibration_chart_nybbles, measure_calibration_chart};
+use eink_convert::color::display_nybbles_to_rgb;
+use eink_convert::dashboard::{local_now, pack_page, Dashboard};
+use eink_convert::container::{Compression, FrameFile};
+use eink_convert::color::ink_palette::InkPalette;
+use eink_convert::dither::{DiffusionSpace, Dither};
+use eink_convert::fit::FitMode;
+use eink_convert::orientation::{Mounting, Orientation};
+use eink_convert::overlay::{Overlay, OverlayPosition, OverlayStage, OverlayText, MIN_OVERLAY_SIZE};
+use eink_convert::panel::PanelProfile;
+use eink_convert::quality::QualityReport;
+use eink_convert::simulation::{Simulation, DEFAULT_DOT_GAIN};
+use eink_convert::{convert, convert_with_report, fingerprint, open_image, read_frame, view_frame, ConvertOptions};
 
 #[derive(Parser)]
-struct Args {
-    file_input: PathBuf,
-    file_output: PathBuf,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    convert: ConvertArgs,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+    // only optional when there's a subcommand instead
+    #[clap(required = true)]
+    file_input: Option<PathBuf>,
+    #[clap(required = true)]
+    file_output: Option<PathBuf>,
     dithered_output: Option<PathBuf>,
+    /// Print how closely the frame reproduces the picture, to compare settings by
+    #[clap(long)]
+    report: bool,
+    #[command(flatten)]
+    simulation: SimulationArgs,
+    #[command(flatten)]
+    settings: ConvertSettings,
+}
+
+// how an image of the frame is rendered, shared by conversion and render
+#[derive(Args)]
+struct SimulationArgs {
+    /// Render the image as the panel will look: its real, muted inks (measured, or typical for the panel) and soft dots
     #[clap(long)]
+    simulate: bool,
+    /// With --simulate, how far ink spreads into neighbouring dots, in pixels
+    #[clap(long, default_value_t = DEFAULT_DOT_GAIN, requires = "simulate")]
+    dot_gain: f32,
+    /// With --simulate, how strong the paper's grain is, from 0 to 1
+    #[clap(long, default_value_t = 0.0, requires = "simulate")]
+    paper_texture: f32,
+}
+
+impl SimulationArgs {
+    fn simulation(&self) -> Option<Simulation> {
+        self.simulate.then_some(Simulation {
+            dot_gain: self.dot_gain,
+            texture: self.paper_texture,
+        })
+    }
+}
+
+// how to convert, shared by single files and batches
+#[derive(Args)]
+struct ConvertSettings {
+    /// Panel to convert for: 13in3-e6 or 7in3-acep
+    #[clap(long, value_parser = PanelProfile::by_name, default_value_t = <&PanelProfile>::default())]
+    panel: &'static PanelProfile,
+    /// Give every pixel its nearest ink without dithering; same as --dither none
+    #[clap(long, conflicts_with = "dither")]
     no_dither: bool,
+    /// Error diffusion (floyd-steinberg, atkinson, jarvis-judice-ninke, stucki, sierra, sierra-lite, burkes),
+    /// ordered (bayer-2, bayer-4, bayer-8, blue-noise) or none
+    #[clap(long, default_value_t = Dither::default())]
+    dither: Dither,
+    /// Colour space error diffusion works in: srgb, linear or oklab
+    #[clap(long, default_value_t = DiffusionSpace::default())]
+    diffusion_space: DiffusionSpace,
+    /// Measured ink colours (TOML, or JSON by extension) for this panel
+    #[clap(long)]
+    palette: Option<PathBuf>,
+    /// fill, stretch, or contain/center with an optional matting, e.g. contain:blur or center:black
+    #[clap(long, default_value_t = FitMode::default())]
+    fit: FitMode,
+    /// Stops brighter, or darker if negative
+    #[clap(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    exposure: f32,
+    /// How far tones spread from mid-grey: 1 leaves them, 0 is flat grey
+    #[clap(long, default_value_t = 1.0)]
+    contrast: f32,
+    /// Above 1 lifts the midtones, below 1 deepens them
+    #[clap(long, default_value_t = 1.0)]
+    gamma: f32,
+    /// 1 leaves colours as they are, 0 is greyscale
+    #[clap(long, default_value_t = 1.0)]
+    saturation: f32,
+    /// Unsharp mask strength, e.g. 0.5; 0 for none
+    #[clap(long, default_value_t = 0.0)]
+    sharpen: f32,
+    /// Size of the detail --sharpen picks out, in pixels
+    #[clap(long, default_value_t = 1.0)]
+    sharpen_radius: f32,
+    /// Lay the picture out auto(matically), landscape or portrait
+    #[clap(long, default_value_t = Orientation::default())]
+    orientation: Orientation,
+    /// How the frame hangs: portrait, landscape, portrait-flipped or landscape-flipped
+    #[clap(long, default_value_t = Mounting::default())]
+    mounting: Mounting,
+    /// For SVG input: give flat fills the nearest ink instead of dithering them
+    #[clap(long)]
+    snap_fills: bool,
+    /// Store the frame as none or deflate
+    #[clap(long, default_value_t = Compression::default())]
+    compression: Compression,
+    /// Print text over the picture: caption or date (from the photo's EXIF), or text:<anything>; repeatable
+    #[clap(long = "overlay")]
+    overlays: Vec<OverlayText>,
+    /// top-left, top, top-right, bottom-left, bottom or bottom-right of the picture as it hangs
+    #[clap(long, default_value_t = OverlayPosition::default())]
+    overlay_position: OverlayPosition,
+    /// Line height in pixels
+    #[clap(long, default_value_t = 48.0, value_parser = parse_overlay_size)]
+    overlay_size: f32,
+    #[clap(long, default_value_t = DisplayColor::White)]
+    overlay_color: DisplayColor,
+    /// Colour of the box behind the text
+    #[clap(long, default_value_t = DisplayColor::Black, conflicts_with = "overlay_no_backing")]
+    overlay_backing: DisplayColor,
+    #[clap(long)]
+    overlay_no_backing: bool,
+    /// Draw the text before dithering, so it's dithered along with the picture
+    #[clap(long)]
+    overlay_before_dither: bool,
+}
+
+fn parse_overlay_size(s: &str) -> Result<f32, String> {
+    let size: f32 = s.parse().map_err(|_| format!("Not a number: {}", s))?;
+    match size >= MIN_OVERLAY_SIZE {
+        true => Ok(size),
+        false => Err(format!("Overlay text must be at least {} pixels high", MIN_OVERLAY_SIZE)),
+    }
+}
+
+impl ConvertSettings {
+    fn overlays(&self) -> Vec<Overlay> {
+        self.overlays
+            .iter()
+            .map(|text| Overlay {
+                text: text.clone(),
+                position: self.overlay_position,
+                size: self.overlay_size,
+                color: self.overlay_color,
+                backing: (!self.overlay_no_backing).then_some(self.overlay_backing),
+                stage: match self.overlay_before_dither {
+                    true => OverlayStage::BeforeDither,
+                    false => OverlayStage::AfterDither,
+                },
+            })
+            .collect()
+    }
+
+    fn options(&self) -> Result<ConvertOptions, Box<dyn Error>> {
+        let palette = match &self.palette {
+            Some(path) => Some(InkPalette::load(path)?),
+            None => None,
+        };
+        Ok(ConvertOptions {
+            panel: self.panel,
+            dither: match self.no_dither {
+                true => Dither::None,
+                false => self.dither,
+            },
+            diffusion_space: self.diffusion_space,
+            palette,
+            fit: self.fit,
+            adjustments: Adjustments {
+                exposure: self.exposure,
+                contrast: self.contrast,
+                gamma: self.gamma,
+                saturation: self.saturation,
+                sharpen: self.sharpen,
+                sharpen_radius: self.sharpen_radius,
+            },
+            orientation: self.orientation,
+            mounting: self.mounting,
+            overlays: self.overlays(),
+            snap_flat_fills: self.snap_fills,
+            compression: self.compression,
+            simulation: None,
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Measure the panel's real ink colours into a palette file
+    Calibrate {
+        #[command(subcommand)]
+        step: CalibrateStep,
+        /// Panel to calibrate: 13in3-e6 or 7in3-acep
+        #[clap(long, global = true, value_parser = PanelProfile::by_name, default_value_t = <&PanelProfile>::default())]
+        panel: &'static PanelProfile,
+    },
+    /// Turn a frame file back into a regular image, reporting any nybbles the panel doesn't know
+    Render {
+        frame: PathBuf,
+        image_output: PathBuf,
+        /// Panel the frame was converted for; sets the size of legacy raw frames and the default inks
+        #[clap(long, value_parser = PanelProfile::by_name, default_value_t = <&PanelProfile>::default())]
+        panel: &'static PanelProfile,
+        /// Render with measured ink colours (TOML, or JSON by extension) instead of the defaults
+        #[clap(long)]
+        palette: Option<PathBuf>,
+        /// Blow every pixel up to a square this many pixels wide
+        #[clap(long, default_value_t = 1)]
+        scale: u32,
+        #[command(flatten)]
+        simulation: SimulationArgs,
+    },
+    /// Render an information page of widgets (TOML, or JSON by extension) as a frame
+    Dashboard {
+        dashboard: PathBuf,
+        frame_output: PathBuf,
+        /// Also save the page, as it hangs, as a regular image
+        preview_output: Option<PathBuf>,
+        #[clap(long, value_parser = PanelProfile::by_name, default_value_t = <&PanelProfile>::default())]
+        panel: &'static PanelProfile,
+        /// How the frame hangs: portrait, landscape, portrait-flipped or landscape-flipped
+        #[clap(long, default_value_t = Mounting::default())]
+        mounting: Mounting,
+        /// Measured ink colours (TOML, or JSON by extension) for this panel
+        #[clap(long)]
+        palette: Option<PathBuf>,
+        /// Store the frame as none or deflate
+        #[clap(long, default_value_t = Compression::default())]
+        compression: Compression,
+    },
+    /// Convert every image in a directory, mirroring it into a directory of frames
+    Batch {
+        input_dir: PathBuf,
+        output_dir: PathBuf,
+        /// Only convert files whose path within the input directory matches, e.g. '*.jpg' or 'trips/**/*.heic'
+        #[clap(long, default_value = "*")]
+        glob: Pattern,
+        /// Look in subdirectories too
+        #[clap(long, short)]
+        recursive: bool,
+        /// Leave out frames that are up to date: never, mtime (newer than the image) or hash (made from the
+        /// same image with the same settings)
+        #[clap(long, value_enum, default_value_t = Skip::Hash)]
+        skip: Skip,
+        /// Conversions at once; one per core when left out
+        #[clap(long)]
+        jobs: Option<usize>,
+        #[command(flatten)]
+        settings: ConvertSettings,
+    },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Skip {
+    Never,
+    Mtime,
+    Hash,
+}
+
+#[derive(Subcommand)]
+enum CalibrateStep {
+    /// Write the calibration chart as a frame for eink-display
+    Chart {
+        frame_output: PathBuf,
+        /// Also save the chart as a regular image
+        preview_output: Option<PathBuf>,
+    },
+    /// Find the chart in a photo of the panel and write the measured palette (TOML, or JSON by extension)
+    Measure {
+        photo: PathBuf,
+        palette_output: PathBuf,
+        /// Palette name; defaults to the output file name
+        #[clap(long)]
+        name: Option<String>,
+        /// Keep the photo's own white balance and exposure instead of making the panel's white pure white
+        #[clap(long)]
+        no_normalize: bool,
+    },
+}
+
+fn run_convert(args: ConvertArgs) -> Result<(), Box<dyn Error>> {
+    let (Some(file_input), Some(file_output)) = (args.file_input, args.file_output) else {
+        unreachable!("clap requires the input and output without a subcommand");
+    };
+    let options = ConvertOptions {
+        simulation: args.simulation.simulation(),
+        ..args.settings.options()?
+    };
+    if args.report {
+        let report = convert_with_report(&file_input, &file_output, args.dithered_output.as_deref(), &options)?;
+        print_report(&report);
+    } else {
+        convert(&file_input, &file_output, args.dithered_output.as_deref(), &options)?;
+    }
+    Ok(())
+}
+
+fn print_report(report: &QualityReport) {
+    println!(
+        "Off by {:.3} on average, {:.3} at the 95th percentile (Oklab, blurred)",
+        report.mean_delta_e, report.p95_delta_e
+    );
+    println!("Out of gamut: {:.1}% of pixels", report.out_of_gamut * 100.0);
+    let pixels = report.pixels().max(1);
+    for (display_color, count) in &report.ink_counts {
+        println!("{:?}: {} pixels ({:.1}%)", display_color, count, *count as f32 * 100.0 / pixels as f32);
+    }
+}
+
+fn run_calibrate(step: CalibrateStep, panel: &PanelProfile) -> Result<(), Box<dyn Error>> {
+    match step {
+        CalibrateStep::Chart { frame_output, preview_output } => {
+            let chart = FrameFile {
+                palette: Some(InkPalette::DEFAULT_NAME.to_string()),
+                ..FrameFile::new(calibration_chart_nybbles(panel))
+            };
+            fs::write(&frame_output, chart.to_bytes(Compression::default()))?;
+            if let Some(preview_output) = preview_output {
+                calibration_chart(panel).save(&preview_output)?;
+            }
+        }
+        CalibrateStep::Measure { photo, palette_output, name, no_normalize } => {
+            let name = name.unwrap_or_else(|| {
+                palette_output
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_else(|| InkPalette::DEFAULT_NAME.to_string())
+            });
+            let calibration = measure_calibration_chart(&open_image(&photo)?, panel, &name, !no_normalize)?;
+            for (display_color, ink) in calibration.palette.inks() {
+                println!("{:?}: rgb {:?}, oklab {:.3} {:.3} {:.3}", display_color, ink.rgb.0, ink.oklab.l, ink.oklab.a, ink.oklab.b);
+            }
+            for mix in &calibration.mixes {
+                println!("{:?}/{:?} mix: off by {:.3} (Oklab)", mix.colors.0, mix.colors.1, mix.delta_e);
+            }
+            calibration.palette.save(&palette_output)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_render(
+    frame: PathBuf,
+    image_output: PathBuf,
+    panel: &PanelProfile,
+    palette: Option<PathBuf>,
+    scale: u32,
+    simulation: Option<Simulation>,
+) -> Result<(), Box<dyn Error>> {
+    let palette = match (&palette, simulation) {
+        (Some(path), _) => InkPalette::load(path)?.for_panel(panel),
+        (None, Some(_)) => panel.typical_palette(),
+        (None, None) => panel.palette(),
+    };
+    let frame_file = read_frame(&frame, panel)?;
+    if let Some(name) = &frame_file.palette {
+        println!("Dithered with palette {}", name);
+    }
+    if let Some(source) = &frame_file.source {
+        println!(
+            "Converted from {} ({}x{})",
+            source.file_name.as_deref().unwrap_or("an unnamed image"),
+            source.width,
+            source.height
+        );
+    }
+    let frame = frame_file.frame;
+    for invalid in frame.invalid_nybbles(panel) {
+        println!(
+            "Invalid nybble {:#X}: {} pixel(s), first at {}x{}",
+            invalid.nybble, invalid.count, invalid.first.0, invalid.first.1
+        );
+    }
+    let rgb = match simulation {
+        Some(simulation) => simulation.render(&frame, &palette),
+        None => display_nybbles_to_rgb(&frame, &palette),
+    };
+    let scale = scale.max(1);
+    if scale == 1 {
+        rgb.save(&image_output)?;
+    } else {
+        resize(&rgb, rgb.width() * scale, rgb.height() * scale, FilterType::Nearest).save(&image_output)?;
+    }
+    Ok(())
+}
+
+fn run_dashboard(
+    dashboard: PathBuf,
+    frame_output: PathBuf,
+    preview_output: Option<PathBuf>,
+    panel: &'static PanelProfile,
+    mounting: Mounting,
+    palette: Option<PathBuf>,
+    compression: Compression,
+) -> Result<(), Box<dyn Error>> {
+    let palette = match &palette {
+        Some(path) => InkPalette::load(path)?.for_panel(panel),
+        None => panel.palette(),
+    };
+    let page = Dashboard::load(&dashboard)?.render_page(local_now(), panel, mounting, &palette)?;
+    if let Some(preview_output) = preview_output {
+        page.save(&preview_output)?;
+    }
+    let frame = FrameFile {
+        palette: Some(palette.name().to_string()),
+        ..FrameFile::new(pack_page(page, panel, mounting, &palette))
+    };
+    fs::write(&frame_output, frame.to_bytes(compression))?;
+    Ok(())
+}
+
+fn run_batch(
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    glob: Pattern,
+    recursive: bool,
+    skip: Skip,
+    jobs: Option<usize>,
+    settings: ConvertSettings,
+) -> Result<(), Box<dyn Error>> {
+    let options = settings.options()?;
+    let match_options = MatchOptions { case_sensitive: false, ..MatchOptions::new() };
+    let mut images = Vec::new();
+    for entry in WalkDir::new(&input_dir).max_depth(if recursive { usize::MAX } else { 1 }) {
+        let entry = entry?;
+        let image = entry.path().strip_prefix(&input_dir)?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type().is_file() && !hidden && glob.matches_path_with(image, match_options) {
+            images.push(image.to_path_buf());
+        }
+    }
+    images.sort();
+    // e.g. a.jpg and a.png would both be written to a.bin, so neither is
+    let mut sources: HashMap<PathBuf, usize> = HashMap::new();
+    for image in &images {
+        *sources.entry(output_dir.join(image).with_extension("bin")).or_default() += 1;
+    }
+
+    // 0 threads means one per core
+    let pool = ThreadPoolBuilder::new().num_threads(jobs.unwrap_or(0)).build()?;
+    let results: Vec<(PathBuf, Result<bool, ImageError>)> = pool.install(|| {
+        images
+            .into_par_iter()
+            .map(|image| {
+                let frame = output_dir.join(&image).with_extension("bin");
+                let result = match sources[&frame] {
+                    1 => convert_stale(&input_dir.join(&image), &frame, skip, &options),
+                    _ => Err(ImageError::IoError(IoError::new(
+                        ErrorKind::AlreadyExists,
+                        format!("Another image also converts to {}", frame.display()),
+                    ))),
+                };
+                (image, result)
+            })
+            .collect()
+    });
+
+    let total = results.len();
+    let converted = results.iter().filter(|(_, result)| matches!(result, Ok(true))).count();
+    let failures: Vec<_> = results.iter().filter_map(|(image, result)| Some((image, result.as_ref().err()?))).collect();
+    println!(
+        "Converted {}, skipped {} up to date, {} failed",
+        converted,
+        total - converted - failures.len(),
+        failures.len()
+    );
+    for (image, err) in &failures {
+        println!("  {}: {}", image.display(), err);
+    }
+    match failures.len() {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} images failed to convert", failed, total).into()),
+    }
+}
+
+/// Converts `image` to `frame` unless `skip` finds the frame up to date; whether it converted.
+fn convert_stale(image: &Path, frame: &Path, skip: Skip, options: &ConvertOptions) -> Result<bool, ImageError> {
+    let up_to_date = match skip {
+        Skip::Never => false,
+        Skip::Mtime => match fs::metadata(frame) {
+            Ok(frame) => frame.modified()? >= fs::metadata(image)?.modified()?,
+            Err(_) => false,
+        },
+        Skip::Hash => match fs::read(frame) {
+            Ok(bytes) => {
+                let made_from = view_frame(&bytes, options.panel).ok().and_then(|view| view.source?.fingerprint);
+                made_from == Some(fingerprint(&fs::read(image)?, options))
+            }
+            Err(_) => false,
+        },
+    };
+    if up_to_date {
+        return Ok(false);
+    }
+    if let Some(dir) = frame.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    convert(image, frame, None, options)?;
+    Ok(true)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt::init();
 
-    let args = Args::parse();
-    convert(&args.file_input, &args.file_output, args.dithered_output.as_deref())?;
-    Ok(())
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Calibrate { step, panel }) => run_calibrate(step, panel),
+        Some(Command::Render { frame, image_output, panel, palette, scale, simulation }) => {
+            run_render(frame, image_output, panel, palette, scale, simulation.simulation())
+        }
+        Some(Command::Dashboard { dashboard, frame_output, preview_output, panel, mounting, palette, compression }) => {
+            run_dashboard(dashboard, frame_output, preview_output, panel, mounting, palette, compression)
+        }
+        Some(Command::Batch { input_dir, output_dir, glob, recursive, skip, jobs, settings }) => {
+            run_batch(input_dir, output_dir, glob, recursive, skip, jobs, settings)
+        }
+        None => run_convert(cli.convert),
+    }
 }
@@ -0,0 +1,151 @@
+//! Tone and detail adjustments made to the picture before it's dithered.
+
+use image::imageops::fast_blur;
+use image::{GrayImage, Rgb, RgbImage};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Oklab lightness of sRGB mid-grey, which contrast spreads tones away from.
+const MID_GREY_L: f32 = 0.6;
+/// Keeps the tone curve from collapsing to a step.
+const MIN_GAMMA: f32 = 0.05;
+
+/// Changes made to the picture before it's dithered. Left out, each leaves the picture alone, so
+/// e.g. `{"contrast": 1.2}` is a whole set.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Adjustments {
+    /// Stops of light added, or taken away if negative.
+    pub exposure: f32,
+    /// How far tones spread from mid-grey: 1 leaves them, 0 is flat grey.
+    pub contrast: f32,
+    /// Bends the tone curve: above 1 lifts the midtones, below 1 deepens them.
+    pub gamma: f32,
+    /// 1 leaves colours as they are, 0 takes them out altogether.
+    pub saturation: f32,
+    /// How much of the fine detail an unsharp mask picks out is added back; 0 for none.
+    pub sharpen: f32,
+    /// Size of the detail sharpening picks out, in pixels.
+    pub sharpen_radius: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            saturation: 1.0,
+            sharpen: 0.0,
+            sharpen_radius: 1.0,
+        }
+    }
+}
+
+impl Adjustments {
+    pub fn is_neutral(&self) -> bool {
+        !self.changes_tone() && !self.sharpens()
+    }
+
+    fn changes_tone(&self) -> bool {
+        self.exposure != 0.0 || self.contrast != 1.0 || self.gamma != 1.0 || self.saturation != 1.0
+    }
+
+    fn sharpens(&self) -> bool {
+        self.sharpen > 0.0 && self.sharpen_radius > 0.0
+    }
+
+    /// Adjusts `img` in place, leaving the pixels set in `fixed` as they are.
+    pub fn apply(&self, img: &mut RgbImage, fixed: Option<&GrayImage>) {
+        let original = fixed.map(|_| img.clone());
+        if self.changes_tone() {
+            self.apply_tone(img);
+        }
+        if self.sharpens() {
+            let blurred = fast_blur(img, self.sharpen_radius);
+            img.par_pixels_mut().zip(blurred.par_pixels()).for_each(|(pixel, blurred)| {
+                for c in 0..3 {
+                    let value = pixel.0[c] as f32;
+                    let detail = value - blurred.0[c] as f32;
+                    pixel.0[c] = (value + self.sharpen * detail).round().clamp(0.0, 255.0) as u8;
+                }
+            });
+        }
+        if let Some((fixed, original)) = fixed.zip(original) {
+            for ((pixel, original), mask) in img.pixels_mut().zip(original.pixels()).zip(fixed.pixels()) {
+                if mask.0[0] != 0 {
+                    *pixel = *original;
+                }
+            }
+        }
+    }
+
+    /// Exposure works on light; the curve and saturation on Oklab, so hues hold steady.
+    fn apply_tone(&self, img: &mut RgbImage) {
+        let gain = 2f32.powf(self.exposure);
+        let inverse_gamma = 1.0 / self.gamma.max(MIN_GAMMA);
+        let contrast = self.contrast.max(0.0);
+        let saturation = self.saturation.max(0.0);
+        img.par_pixels_mut().for_each(|pixel| {
+            let linear: LinSrgb = Srgb::new(pixel.0[0], pixel.0[1], pixel.0[2]).into_format::<f32>().into_linear();
+            let mut oklab: Oklab = (linear * gain).into_color();
+            let lightness = oklab.l.clamp(0.0, 1.0).powf(inverse_gamma);
+            oklab.l = (lightness - MID_GREY_L) * contrast + MID_GREY_L;
+            oklab.a *= saturation;
+            oklab.b *= saturation;
+            let srgb: Srgb = oklab.into_color();
+            *pixel = Rgb([srgb.red, srgb.green, srgb.blue].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(64, 64, |x, y| Rgb([x as u8 * 4, y as u8 * 4, (x + y) as u8 * 2]))
+    }
+
+    #[test]
+    fn defaults_leave_the_picture_alone() {
+        let adjustments = Adjustments::default();
+        assert!(adjustments.is_neutral());
+        let mut img = gradient();
+        adjustments.apply(&mut img, None);
+        assert_eq!(img, gradient());
+    }
+
+    #[test]
+    fn neutral_tone_curve_is_the_identity() {
+        let mut img = gradient();
+        Adjustments::default().apply_tone(&mut img);
+        for (got, wanted) in img.pixels().zip(gradient().pixels()) {
+            let off = (0..3).map(|c| got.0[c].abs_diff(wanted.0[c])).max().unwrap();
+            assert!(off <= 1, "{:?} came out {:?}", wanted, got);
+        }
+    }
+
+    #[test]
+    fn left_out_fields_are_neutral() {
+        let adjustments: Adjustments = serde_json::from_str(r#"{"contrast": 1.2}"#).unwrap();
+        assert_eq!(adjustments, Adjustments { contrast: 1.2, ..Adjustments::default() });
+    }
+
+    #[test]
+    fn fixed_pixels_are_kept() {
+        let fixed = GrayImage::from_fn(64, 64, |x, _| Luma([if x < 32 { 255 } else { 0 }]));
+        let adjustments = Adjustments { exposure: 1.0, sharpen: 1.0, ..Adjustments::default() };
+        let original = gradient();
+        let mut img = original.clone();
+        adjustments.apply(&mut img, Some(&fixed));
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if x < 32 {
+                assert_eq!(pixel, original.get_pixel(x, y));
+            }
+        }
+        assert_ne!(img, original);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::{Ink, InkPalette};
+use crate::color::rgb_to_display_nybbles;
+use crate::frame::EpdFrame;
+use crate::panel::PanelProfile;
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use imageproc::contrast::{otsu_level, threshold, ThresholdType};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::geometric_transformations::Projection;
+use imageproc::rect::Rect;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use thiserror::Error;
+use tracing::info;
+
+/// Chart sizes below are for a panel this many pixels across its short side, and scale with it.
+const LAYOUT_SHORT_SIDE: u32 = 1_200;
+const MARGIN: u32 = 40;
+const MARKER_SIZE: u32 = 80;
+/// The top-left marker is bigger than the other three so the chart's orientation can be told
+/// apart in a photo, whichever way round the frame is hanging.
+const ORIGIN_MARKER_SIZE: u32 = 120;
+const GUTTER: u32 = 20;
+/// Patch columns on a portrait panel; landscape panels get twice as many.
+const GRID_COLUMNS: u32 = 3;
+
+/// Photos are shrunk to fit this before looking for markers; the patches are big enough to survive it.
+const MEASURE_SIZE: u32 = 1_000;
+/// Anything smaller is noise, not a marker.
+const MIN_MARKER_PIXELS: u32 = 30;
+/// Samples per side taken from the middle half of each patch.
+const SAMPLES_PER_SIDE: u32 = 8;
+
+#[derive(Debug, Error)]
+pub enum CalibrationError {
+    #[error("Found {0} of the 4 corner markers. Is the whole chart in the photo?")]
+    MarkersNotFound(usize),
+    #[error("Corner markers do not form a usable quadrilateral")]
+    DegenerateMarkers,
+    #[error("Patch {0:?} lies outside the photo")]
+    PatchOutsidePhoto(Patch),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Patch {
+    Solid(DisplayColor),
+    /// A 50/50 checkerboard of two inks.
+    Mix(DisplayColor, DisplayColor),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MixMeasurement {
+    pub colors: (DisplayColor, DisplayColor),
+    /// Oklab distance between the photographed mix and the average of its two measured inks.
+    /// Large values mean dot gain or bleed that the palette alone can't model.
+    pub delta_e: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    pub palette: InkPalette,
+    pub mixes: Vec<MixMeasurement>,
+}
+
+fn patches(panel: &PanelProfile) -> Vec<Patch> {
+    let colors: Vec<DisplayColor> = panel.colors().collect();
+    let mut patches: Vec<Patch> = colors.iter().copied().map(Patch::Solid).collect();
+    for (i, a) in colors.iter().enumerate() {
+        for b in colors.iter().skip(i + 1) {
+            patches.push(Patch::Mix(*a, *b));
+        }
+    }
+    patches
+}
+
+/// Where everything goes on `panel`'s chart.
+struct ChartLayout {
+    width: u32,
+    height: u32,
+    margin: u32,
+    marker_size: u32,
+    origin_marker_size: u32,
+    gutter: u32,
+    columns: u32,
+}
+
+impl ChartLayout {
+    fn new(panel: &PanelProfile) -> Self {
+        let scale = |size: u32| size * panel.width.min(panel.height) / LAYOUT_SHORT_SIDE;
+        Self {
+            width: panel.width,
+            height: panel.height,
+            margin: scale(MARGIN),
+            marker_size: scale(MARKER_SIZE),
+            origin_marker_size: scale(ORIGIN_MARKER_SIZE),
+            gutter: scale(GUTTER),
+            columns: if panel.is_landscape() { 2 * GRID_COLUMNS } else { GRID_COLUMNS },
+        }
+    }
+
+    fn grid_top(&self) -> u32 {
+        self.margin + self.origin_marker_size + self.margin
+    }
+
+    fn patch_rects(&self, patches: Vec<Patch>) -> Vec<(Patch, Rect)> {
+        let rows = (patches.len() as u32).div_ceil(self.columns);
+        let cell_width = (self.width - 2 * self.margin) / self.columns;
+        let cell_height = (self.height - 2 * self.grid_top()) / rows;
+        patches
+            .into_iter()
+            .enumerate()
+            .map(|(i, patch)| {
+                let column = i as u32 % self.columns;
+                let row = i as u32 / self.columns;
+                let rect = Rect::at(
+                    (self.margin + column * cell_width + self.gutter / 2) as i32,
+                    (self.grid_top() + row * cell_height + self.gutter / 2) as i32,
+                )
+                .of_size(cell_width - self.gutter, cell_height - self.gutter);
+                (patch, rect)
+            })
+            .collect()
+    }
+
+    /// Marker squares in chart order: top-left (the big one), top-right, bottom-right, bottom-left.
+    fn marker_rects(&self) -> [Rect; 4] {
+        let far_x = (self.width - self.margin - self.marker_size) as i32;
+        let far_y = (self.height - self.margin - self.marker_size) as i32;
+        let margin = self.margin as i32;
+        [
+            Rect::at(margin, margin).of_size(self.origin_marker_size, self.origin_marker_size),
+            Rect::at(far_x, margin).of_size(self.marker_size, self.marker_size),
+            Rect::at(far_x, far_y).of_size(self.marker_size, self.marker_size),
+            Rect::at(margin, far_y).of_size(self.marker_size, self.marker_size),
+        ]
+    }
+}
+
+fn rect_center(rect: &Rect) -> (f32, f32) {
+    (
+        rect.left() as f32 + rect.width() as f32 / 2.0,
+        rect.top() as f32 + rect.height() as f32 / 2.0,
+    )
+}
+
+/// The calibration chart in `panel`'s native orientation, drawn with its nominal colours so it
+/// packs straight into a frame.
+pub fn calibration_chart(panel: &PanelProfile) -> RgbImage {
+    let palette = panel.palette();
+    let layout = ChartLayout::new(panel);
+    let mut chart = RgbImage::from_pixel(panel.width, panel.height, palette.rgb(DisplayColor::White));
+    for rect in layout.marker_rects() {
+        draw_filled_rect_mut(&mut chart, rect, palette.rgb(DisplayColor::Black));
+    }
+    for (patch, rect) in layout.patch_rects(patches(panel)) {
+        match patch {
+            Patch::Solid(display_color) => draw_filled_rect_mut(&mut chart, rect, palette.rgb(display_color)),
+            Patch::Mix(a, b) => {
+                for y in rect.top()..=rect.bottom() {
+                    for x in rect.left()..=rect.right() {
+                        let display_color = if (x + y) % 2 == 0 { a } else { b };
+                        chart.put_pixel(x as u32, y as u32, palette.rgb(display_color));
+                    }
+                }
+            }
+        }
+    }
+    chart
+}
+
+pub fn calibration_chart_nybbles(panel: &PanelProfile) -> EpdFrame {
+    rgb_to_display_nybbles(&calibration_chart(panel), &panel.palette())
+}
+
+#[derive(Debug, Default)]
+struct Blob {
+    pixels: u32,
+    min: (u32, u32),
+    max: (u32, u32),
+    sum: (u64, u64),
+}
+
+/// Centres of the four corner markers in `gray`, in the same order as `ChartLayout::marker_rects`.
+fn find_markers(gray: &GrayImage) -> Result<[(f32, f32); 4], CalibrationError> {
+    let dark = threshold(gray, otsu_level(gray), ThresholdType::BinaryInverted);
+    let labels = connected_components(&dark, Connectivity::Eight, Luma([0u8]));
+    let mut blobs: HashMap<u32, Blob> = HashMap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label.0[0] == 0 {
+            continue;
+        }
+        let blob = blobs.entry(label.0[0]).or_insert_with(|| Blob {
+            min: (x, y),
+            max: (x, y),
+            ..Blob::default()
+        });
+        blob.pixels += 1;
+        blob.min = (blob.min.0.min(x), blob.min.1.min(y));
+        blob.max = (blob.max.0.max(x), blob.max.1.max(y));
+        blob.sum = (blob.sum.0 + x as u64, blob.sum.1 + y as u64);
+    }
+
+    // markers are the biggest solid, square blobs; patches are too wide and the bezel is hollow
+    let mut markers: Vec<Blob> = blobs
+        .into_values()
+        .filter(|blob| {
+            let width = (blob.max.0 - blob.min.0 + 1) as f32;
+            let height = (blob.max.1 - blob.min.1 + 1) as f32;
+            let aspect = width / height;
+            let fill = blob.pixels as f32 / (width * height);
+            blob.pixels >= MIN_MARKER_PIXELS && (0.75..=1.33).contains(&aspect) && fill >= 0.75
+        })
+        .collect();
+    markers.sort_by_key(|blob| Reverse(blob.pixels));
+    markers.truncate(4);
+    if markers.len() < 4 {
+        return Err(CalibrationError::MarkersNotFound(markers.len()));
+    }
+
+    let centers: Vec<(f32, f32)> = markers
+        .iter()
+        .map(|blob| (blob.sum.0 as f32 / blob.pixels as f32, blob.sum.1 as f32 / blob.pixels as f32))
+        .collect();
+    let mean = (
+        centers.iter().map(|c| c.0).sum::<f32>() / 4.0,
+        centers.iter().map(|c| c.1).sum::<f32>() / 4.0,
+    );
+    let angle = |c: &(f32, f32)| (c.1 - mean.1).atan2(c.0 - mean.0);
+    let origin_angle = angle(&centers[0]);
+    // clockwise on screen starting from the origin marker, which is how the chart lists them
+    let mut ordered = centers.clone();
+    ordered.sort_by(|a, b| {
+        let a = (angle(a) - origin_angle).rem_euclid(std::f32::consts::TAU);
+        let b = (angle(b) - origin_angle).rem_euclid(std::f32::consts::TAU);
+        a.total_cmp(&b)
+    });
+    Ok([ordered[0], ordered[1], ordered[2], ordered[3]])
+}
+
+fn srgb_to_linear(rgb: &Rgb<u8>) -> [f32; 3] {
+    let linear: LinSrgb = Srgb::new(rgb.0[0], rgb.0[1], rgb.0[2]).into_format::<f32>().into_linear();
+    [linear.red, linear.green, linear.blue]
+}
+
+fn linear_to_oklab(linear: [f32; 3]) -> Oklab {
+    LinSrgb::new(linear[0], linear[1], linear[2]).into_color()
+}
+
+/// Average linear colour of the middle of `rect` (in chart coordinates) as seen in `photo`.
+fn sample_patch(photo: &RgbImage, chart_to_photo: Projection, patch: Patch, rect: &Rect) -> Result<[f32; 3], CalibrationError> {
+    let mut total = [0f32; 3];
+    let mut count = 0;
+    for sy in 0..SAMPLES_PER_SIDE {
+        for sx in 0..SAMPLES_PER_SIDE {
+            let chart_x = rect.left() as f32 + rect.width() as f32 * (0.25 + 0.5 * (sx as f32 + 0.5) / SAMPLES_PER_SIDE as f32);
+            let chart_y = rect.top() as f32 + rect.height() as f32 * (0.25 + 0.5 * (sy as f32 + 0.5) / SAMPLES_PER_SIDE as f32);
+            let (x, y) = chart_to_photo * (chart_x, chart_y);
+            if x < 0.0 || y < 0.0 || x >= photo.width() as f32 || y >= photo.height() as f32 {
+                continue;
+            }
+            let linear = srgb_to_linear(photo.get_pixel(x as u32, y as u32));
+            for c in 0..3 {
+                total[c] += linear[c];
+            }
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return Err(CalibrationError::PatchOutsidePhoto(patch));
+    }
+    Ok(total.map(|c| c / count as f32))
+}
+
+/// Finds the chart in a photo of the panel and measures every ink.
+///
+/// With `normalize`, the photo's white balance and exposure are corrected so the measured paper
+/// white becomes pure white and the other inks keep their brightness relative to it. Without
+/// it, the colours are taken as the camera saw them.
+pub fn measure_calibration_chart(
+    photo: &DynamicImage,
+    panel: &PanelProfile,
+    name: &str,
+    normalize: bool,
+) -> Result<Calibration, CalibrationError> {
+    let layout = ChartLayout::new(panel);
+    let photo = photo.resize(MEASURE_SIZE, MEASURE_SIZE, FilterType::Triangle);
+    let rgb = photo.to_rgb8();
+    let markers = find_markers(&photo.to_luma8())?;
+    info!("Found chart markers at {:?}", markers);
+    let chart_markers = layout.marker_rects().map(|rect| rect_center(&rect));
+    let chart_to_photo =
+        Projection::from_control_points(chart_markers, markers).ok_or(CalibrationError::DegenerateMarkers)?;
+
+    let measured_patches = layout
+        .patch_rects(patches(panel))
+        .into_iter()
+        .map(|(patch, rect)| Ok((patch, sample_patch(&rgb, chart_to_photo, patch, &rect)?)))
+        .collect::<Result<Vec<_>, CalibrationError>>()?;
+    let measured: HashMap<Patch, [f32; 3]> = measured_patches.iter().copied().collect();
+
+    let white = measured[&Patch::Solid(DisplayColor::White)];
+    let gain = if normalize {
+        white.map(|c| 1.0 / c.max(f32::EPSILON))
+    } else {
+        [1.0; 3]
+    };
+    let corrected = |linear: [f32; 3]| [0, 1, 2].map(|c| linear[c] * gain[c]);
+
+    let inks = panel.colors().map(|display_color| {
+        let linear = corrected(measured[&Patch::Solid(display_color)]);
+        (display_color, Ink::from_oklab(linear_to_oklab(linear)))
+    });
+    let mixes = measured_patches
+        .iter()
+        .filter_map(|(patch, linear)| match patch {
+            Patch::Mix(a, b) => Some((*a, *b, corrected(*linear))),
+            Patch::Solid(_) => None,
+        })
+        .map(|(a, b, linear)| {
+            let ink_a = corrected(measured[&Patch::Solid(a)]);
+            let ink_b = corrected(measured[&Patch::Solid(b)]);
+            let expected = linear_to_oklab([0, 1, 2].map(|c| (ink_a[c] + ink_b[c]) / 2.0));
+            let actual = linear_to_oklab(linear);
+            let delta_e = ((expected.l - actual.l).powi(2) + (expected.a - actual.a).powi(2) + (expected.b - actual.b).powi(2)).sqrt();
+            MixMeasurement {
+                colors: (a, b),
+                delta_e,
+            }
+        })
+        .collect();
+
+    Ok(Calibration {
+        palette: InkPalette::new(name, inks),
+        mixes,
+    })
+}
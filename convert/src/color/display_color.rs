@@ -1,19 +1,47 @@
 use image::Rgb;
-use palette::{IntoColor, Oklab, Srgb};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[repr(u8)]
 pub enum DisplayColor {
     Black = 0x00,
     White = 0x01,
     Yellow = 0x02,
     Red = 0x03,
+    /// Only on seven-colour ACeP panels.
+    Orange = 0x04,
     Blue = 0x05,
     Green = 0x06,
 }
 
 impl DisplayColor {
+    pub const ALL: [DisplayColor; 7] = [
+        DisplayColor::Black,
+        DisplayColor::White,
+        DisplayColor::Yellow,
+        DisplayColor::Red,
+        DisplayColor::Blue,
+        DisplayColor::Green,
+        DisplayColor::Orange,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            DisplayColor::Black => "black",
+            DisplayColor::White => "white",
+            DisplayColor::Yellow => "yellow",
+            DisplayColor::Red => "red",
+            DisplayColor::Orange => "orange",
+            DisplayColor::Blue => "blue",
+            DisplayColor::Green => "green",
+        }
+    }
+
     pub fn rgb_map() -> HashMap<DisplayColor, Rgb<u8>> {
         HashMap::from([
             (DisplayColor::Black, Rgb::from([0, 0, 0])),
@@ -22,15 +50,33 @@ impl DisplayColor {
             (DisplayColor::Red, Rgb::from([191, 2, 1])),
             (DisplayColor::Blue, Rgb::from([100, 64, 255])),
             (DisplayColor::Green, Rgb::from([68, 138, 28])),
+            (DisplayColor::Orange, Rgb::from([255, 128, 0])),
         ])
     }
 }
 
+impl Display for DisplayColor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DisplayColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DisplayColor::ALL
+            .into_iter()
+            .find(|display_color| display_color.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown colour: {}", s))
+    }
+}
+
 impl From<DisplayColor> for Rgb<u8> {
     fn from(value: DisplayColor) -> Self {
         DisplayColor::rgb_map()
             .get(&value)
-            .map(|v| v.clone())
+            .copied()
             .expect("Should be impossible")
     }
 }
@@ -42,15 +88,6 @@ impl From<DisplayColor> for Oklab {
     }
 }
 
-impl From<&Rgb<u8>> for DisplayColor {
-    fn from(value: &Rgb<u8>) -> Self {
-        DisplayColor::rgb_map()
-            .iter()
-            .find_map(|(k, v)| if value == v { Some(k.clone()) } else { None })
-            .expect("Mapping color from rgb failed. (Should be impossible)")
-    }
-}
-
 impl From<usize> for DisplayColor {
     fn from(value: usize) -> Self {
         match value {
@@ -58,6 +95,7 @@ impl From<usize> for DisplayColor {
             1 => DisplayColor::White,
             2 => DisplayColor::Yellow,
             3 => DisplayColor::Red,
+            4 => DisplayColor::Orange,
             5 => DisplayColor::Blue,
             6 => DisplayColor::Green,
             _ => DisplayColor::White,
@@ -65,6 +103,19 @@ impl From<usize> for DisplayColor {
     }
 }
 
+/// Fails with the nybble itself if it isn't a colour any panel knows; whether a particular panel
+/// has it is up to [`PanelProfile::colors`](crate::panel::PanelProfile::colors).
+impl TryFrom<u8> for DisplayColor {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        DisplayColor::ALL
+            .into_iter()
+            .find(|display_color| u8::from(*display_color) == value)
+            .ok_or(value)
+    }
+}
+
 impl From<DisplayColor> for u8 {
     fn from(value: DisplayColor) -> Self {
         match value {
@@ -72,6 +123,7 @@ impl From<DisplayColor> for u8 {
             DisplayColor::White => 1,
             DisplayColor::Yellow => 2,
             DisplayColor::Red => 3,
+            DisplayColor::Orange => 4,
             DisplayColor::Blue => 5,
             DisplayColor::Green => 6,
         }
@@ -79,9 +131,7 @@ impl From<DisplayColor> for u8 {
 }
 
 pub fn rgb_to_oklab(rgb: Rgb<u8>) -> Oklab {
-    let r = rgb.0[0] as f32 / u8::MAX as f32;
-    let g = rgb.0[1] as f32 / u8::MAX as f32;
-    let b = rgb.0[2] as f32 / u8::MAX as f32;
-    let srgb = Srgb::from((r, g, b));
-    srgb.into_color()
+    // u8 channels linearise through a lookup table
+    let linear: LinSrgb = Srgb::new(rgb.0[0], rgb.0[1], rgb.0[2]).into_linear();
+    linear.into_color()
 }
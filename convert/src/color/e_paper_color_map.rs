@@ -1,50 +1,72 @@
-use crate::color::display_color::{rgb_to_oklab, DisplayColor};
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use crate::color::nearest_lut::NearestInkLut;
 use image::imageops::ColorMap;
 use image::Rgb;
 use palette::color_difference::HyAb;
 use palette::Oklab;
-use std::collections::HashMap;
+use std::sync::Arc;
 
 pub struct EPaperColorMap {
-    colormap: HashMap<DisplayColor, Oklab>,
+    palette: InkPalette,
+    lut: Arc<NearestInkLut>,
 }
 
 impl EPaperColorMap {
     pub fn new() -> Self {
-        let colors = vec![
-            DisplayColor::Black,
-            DisplayColor::White,
-            DisplayColor::Yellow,
-            DisplayColor::Red,
-            DisplayColor::Blue,
-            DisplayColor::Green,
-        ];
+        Self::with_palette(InkPalette::default())
+    }
+
+    /// Builds the palette's lookup table straight away, so it's ready before dithering goes
+    /// parallel.
+    pub fn with_palette(palette: InkPalette) -> Self {
         Self {
-            colormap: HashMap::from_iter(colors.into_iter().map(|c| (c, c.into()))),
+            lut: NearestInkLut::for_palette(&palette),
+            palette,
         }
     }
+
+    pub fn palette(&self) -> &InkPalette {
+        &self.palette
+    }
+
+    pub fn colors(&self) -> impl Iterator<Item = (DisplayColor, Oklab)> + '_ {
+        self.palette.inks().map(|(display_color, ink)| (display_color, ink.oklab))
+    }
+
+    pub fn nearest(&self, color: Oklab) -> DisplayColor {
+        self.palette.nearest(color)
+    }
+
+    /// Like [`EPaperColorMap::nearest`], from the palette's lookup table.
+    pub fn nearest_rgb(&self, color: Rgb<u8>) -> DisplayColor {
+        self.lut.nearest(color)
+    }
+
+    /// The two palette entries closest to `color`, nearest first.
+    pub fn nearest_pair(&self, color: Oklab) -> [(DisplayColor, Oklab); 2] {
+        let mut entries: Vec<(DisplayColor, Oklab)> = self.colors().collect();
+        entries.sort_by(|(_, a), (_, b)| a.hybrid_distance(color).total_cmp(&b.hybrid_distance(color)));
+        [entries[0], entries[1]]
+    }
+}
+
+impl Default for EPaperColorMap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ColorMap for EPaperColorMap {
     type Color = Rgb<u8>; // dither requires this to be u8
 
     fn index_of(&self, color: &Self::Color) -> usize {
-        let oklab_color: Oklab = rgb_to_oklab(*color);
-        let color = self
-            .colormap
-            .iter()
-            .min_by(|(_, a), (_, b)| {
-                a.hybrid_distance(oklab_color.clone())
-                    .total_cmp(&b.hybrid_distance(oklab_color.clone()))
-            })
-            .map(|(index, _)| index)
-            .expect("Color not found in map");
-        *color as usize
+        self.nearest_rgb(*color) as usize
     }
 
     fn lookup(&self, index: usize) -> Option<Self::Color> {
         let display_color: DisplayColor = DisplayColor::from(index);
-        Some(display_color.into())
+        Some(self.palette.rgb(display_color))
     }
 
     fn has_lookup(&self) -> bool {
@@ -52,8 +74,7 @@ impl ColorMap for EPaperColorMap {
     }
 
     fn map_color(&self, color: &mut Self::Color) {
-        let c = color.clone();
-        let new_color = self.lookup(self.index_of(&c)).expect("Infallible");
+        let new_color = self.lookup(self.index_of(color)).expect("Infallible");
         *color = new_color;
     }
 }
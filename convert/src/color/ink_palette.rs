@@ -0,0 +1,186 @@
+use crate::color::display_color::{rgb_to_oklab, DisplayColor};
+use crate::panel::PanelProfile;
+use image::Rgb;
+use palette::color_difference::HyAb;
+use palette::{IntoColor, Oklab, Srgb};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Error as IoError;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PaletteError {
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    TomlRead(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlWrite(#[from] toml::ser::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// A measured ink as written in a palette file, in whichever space it was measured in.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum InkEntry {
+    Srgb { srgb: [u8; 3] },
+    Oklab { oklab: [f32; 3] },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PaletteFile {
+    name: Option<String>,
+    #[serde(default)]
+    colors: BTreeMap<DisplayColor, InkEntry>,
+}
+
+/// How one `DisplayColor` actually looks on the panel.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ink {
+    pub rgb: Rgb<u8>,
+    pub oklab: Oklab,
+}
+
+impl Ink {
+    pub fn from_rgb(rgb: Rgb<u8>) -> Self {
+        Self {
+            rgb,
+            oklab: rgb_to_oklab(rgb),
+        }
+    }
+
+    /// Measured colours can fall outside sRGB; the rendered `rgb` is clamped, `oklab` is kept as is.
+    pub fn from_oklab(oklab: Oklab) -> Self {
+        let srgb: Srgb = oklab.into_color();
+        let rgb = Rgb::from([srgb.red, srgb.green, srgb.blue].map(|c| (c * u8::MAX as f32).round().clamp(0.0, 255.0) as u8));
+        Self { rgb, oklab }
+    }
+}
+
+/// The colour of every `DisplayColor` on a particular panel. Colours are matched against `oklab`
+/// and previews are rendered with `rgb`. Files may leave colours out, which keep the panel's
+/// defaults once the palette is fitted to it with [`InkPalette::for_panel`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct InkPalette {
+    name: String,
+    inks: HashMap<DisplayColor, Ink>,
+}
+
+impl InkPalette {
+    pub const DEFAULT_NAME: &'static str = "default";
+
+    pub fn new(name: &str, inks: impl IntoIterator<Item = (DisplayColor, Ink)>) -> Self {
+        Self {
+            name: name.to_string(),
+            inks: inks.into_iter().collect(),
+        }
+    }
+
+    /// Exactly `panel`'s inks: measured ones from this palette, the panel's defaults for the rest.
+    pub fn for_panel(&self, panel: &PanelProfile) -> Self {
+        let defaults = panel.palette();
+        Self::new(
+            &self.name,
+            panel
+                .colors()
+                .map(|display_color| (display_color, *self.inks.get(&display_color).unwrap_or(&defaults.ink(display_color)))),
+        )
+    }
+
+    /// Reads a palette from a `.json` file, or TOML for any other extension.
+    pub fn load(path: &Path) -> Result<Self, PaletteError> {
+        let contents = fs::read_to_string(path)?;
+        let file: PaletteFile = if is_json(path) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        let name = file.name.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| Self::DEFAULT_NAME.to_string())
+        });
+        let inks = file.colors.into_iter().map(|(display_color, entry)| {
+            let ink = match entry {
+                InkEntry::Srgb { srgb } => Ink::from_rgb(Rgb::from(srgb)),
+                InkEntry::Oklab { oklab: [l, a, b] } => Ink::from_oklab(Oklab::new(l, a, b)),
+            };
+            (display_color, ink)
+        });
+        Ok(Self::new(&name, inks))
+    }
+
+    /// Writes every ink as Oklab, so nothing measured outside sRGB is lost.
+    pub fn save(&self, path: &Path) -> Result<(), PaletteError> {
+        let file = PaletteFile {
+            name: Some(self.name.clone()),
+            colors: self
+                .inks()
+                .map(|(display_color, ink)| {
+                    let oklab = [ink.oklab.l, ink.oklab.a, ink.oklab.b];
+                    (display_color, InkEntry::Oklab { oklab })
+                })
+                .collect(),
+        };
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(&file)?
+        } else {
+            toml::to_string(&file)?
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Colours the palette doesn't have fall back to their nominal colour.
+    pub fn ink(&self, display_color: DisplayColor) -> Ink {
+        self.inks
+            .get(&display_color)
+            .copied()
+            .unwrap_or_else(|| Ink::from_rgb(display_color.into()))
+    }
+
+    pub fn rgb(&self, display_color: DisplayColor) -> Rgb<u8> {
+        self.ink(display_color).rgb
+    }
+
+    pub fn oklab(&self, display_color: DisplayColor) -> Oklab {
+        self.ink(display_color).oklab
+    }
+
+    pub fn inks(&self) -> impl Iterator<Item = (DisplayColor, Ink)> + '_ {
+        DisplayColor::ALL
+            .into_iter()
+            .filter_map(|display_color| Some((display_color, *self.inks.get(&display_color)?)))
+    }
+
+    /// The `DisplayColor` whose rendered colour is exactly `rgb`, if any.
+    pub fn display_color_of(&self, rgb: &Rgb<u8>) -> Option<DisplayColor> {
+        self.inks().find_map(|(display_color, ink)| (ink.rgb == *rgb).then_some(display_color))
+    }
+
+    /// The ink that looks closest to `color`.
+    pub fn nearest(&self, color: Oklab) -> DisplayColor {
+        self.inks()
+            .min_by(|(_, a), (_, b)| a.oklab.hybrid_distance(color).total_cmp(&b.oklab.hybrid_distance(color)))
+            .map(|(display_color, _)| display_color)
+            .expect("Palette has no inks")
+    }
+}
+
+/// The default panel's uncalibrated inks.
+impl Default for InkPalette {
+    fn default() -> Self {
+        <&PanelProfile>::default().palette()
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
@@ -1,20 +1,19 @@
-mod display_color;
+pub mod display_color;
 pub mod e_paper_color_map;
 pub mod color_histogram_eq;
+pub mod ink_palette;
+pub mod nearest_lut;
 
-use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use crate::frame::EpdFrame;
 use image::RgbImage;
 
-pub fn rgb_to_display_nybbles(rgb: &RgbImage) -> Vec<u8> {
-    let mut pix = Vec::with_capacity(rgb.len() / 2);
-    for chunk in
-    rgb.pixels()
-        .map(DisplayColor::from)
-        .collect::<Vec<_>>()
-        .chunks(2)
-    {
-        let packed_byte = (u8::from(chunk[0])) << 4 | u8::from(chunk[1]);
-        pix.push(packed_byte);
-    }
-    pix
+/// Anything in `rgb` not rendered from `palette` is packed as the nearest ink.
+pub fn rgb_to_display_nybbles(rgb: &RgbImage, palette: &InkPalette) -> EpdFrame {
+    EpdFrame::from_rgb(rgb, palette)
+}
+
+/// The inverse of [`rgb_to_display_nybbles`]: renders `frame` in `palette`'s colours.
+pub fn display_nybbles_to_rgb(frame: &EpdFrame, palette: &InkPalette) -> RgbImage {
+    frame.to_rgb(palette)
 }
@@ -0,0 +1,120 @@
+//! The nearest ink to every 8-bit colour, looked up rather than measured against every ink for
+//! every pixel.
+
+use crate::color::display_color::{rgb_to_oklab, DisplayColor};
+use crate::color::ink_palette::InkPalette;
+use image::Rgb;
+use rayon::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Cells along each channel; each covers 4 values.
+const CELLS: usize = 64;
+const CELL_SIZE: usize = 256 / CELLS;
+/// A cell whose corners disagree, i.e. one a decision boundary runs through.
+const BOUNDARY: u8 = u8::MAX;
+/// Palettes kept built; a run rarely uses more than one.
+const CACHED: usize = 4;
+
+static CACHE: Mutex<Vec<Arc<NearestInkLut>>> = Mutex::new(Vec::new());
+
+/// Nearest inks for a palette over a 64³ grid of cells. Cells the same ink is nearest at every
+/// corner of, and at every corner of their neighbours, are taken as that ink throughout; the rest
+/// are worked out per colour. A boundary could in principle curve through a whole neighbourhood
+/// without touching a corner; the tests check every 8-bit colour against the panels' own palettes.
+#[derive(Debug)]
+pub struct NearestInkLut {
+    palette: InkPalette,
+    cells: Vec<u8>,
+}
+
+impl NearestInkLut {
+    pub fn new(palette: &InkPalette) -> Self {
+        // corners sit on the cell edges, the last one on the brightest value
+        let corner = |i: usize| (i * CELL_SIZE).min(u8::MAX as usize) as u8;
+        let corners = CELLS + 1;
+        let nearest: Vec<u8> = (0..corners * corners * corners)
+            .into_par_iter()
+            .map(|i| {
+                let (r, g, b) = (i / (corners * corners), i / corners % corners, i % corners);
+                palette.nearest(rgb_to_oklab(Rgb([corner(r), corner(g), corner(b)]))) as u8
+            })
+            .collect();
+        let at = |r: usize, g: usize, b: usize| nearest[(r * corners + g) * corners + b];
+
+        // a boundary can still slip between a cell's corners, so the cells around it count too,
+        // i.e. every corner of the cell's neighbours has to agree
+        let around = |i: usize| i.saturating_sub(1)..=(i + 2).min(CELLS);
+        let cells = (0..CELLS * CELLS * CELLS)
+            .into_par_iter()
+            .map(|i| {
+                let (r, g, b) = (i / (CELLS * CELLS), i / CELLS % CELLS, i % CELLS);
+                let ink = at(r, g, b);
+                let uniform = around(r).all(|r| around(g).all(|g| around(b).all(|b| at(r, g, b) == ink)));
+                if uniform { ink } else { BOUNDARY }
+            })
+            .collect();
+        Self {
+            palette: palette.clone(),
+            cells,
+        }
+    }
+
+    /// Built once per palette and shared from then on. Building runs across threads, so call it
+    /// before going parallel rather than from inside a parallel loop.
+    pub fn for_palette(palette: &InkPalette) -> Arc<Self> {
+        if let Some(lut) = Self::cached(palette) {
+            return lut;
+        }
+        // the cache isn't held while building, so two threads may both build the same table;
+        // whichever finishes second uses the first one's
+        let lut = Arc::new(Self::new(palette));
+        let mut cache = CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(lut) = cache.iter().find(|cached| cached.palette == *palette) {
+            return lut.clone();
+        }
+        if cache.len() == CACHED {
+            cache.remove(0);
+        }
+        cache.push(lut.clone());
+        lut
+    }
+
+    fn cached(palette: &InkPalette) -> Option<Arc<Self>> {
+        let cache = CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        cache.iter().find(|lut| lut.palette == *palette).cloned()
+    }
+
+    pub fn nearest(&self, rgb: Rgb<u8>) -> DisplayColor {
+        let [r, g, b] = rgb.0.map(|c| c as usize / CELL_SIZE);
+        match self.cells[(r * CELLS + g) * CELLS + b] {
+            BOUNDARY => self.palette.nearest(rgb_to_oklab(rgb)),
+            ink => DisplayColor::from(ink as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::panel::{ACEP_7IN3, SPECTRA_6_13IN3};
+
+    fn assert_exact(palette: &InkPalette) {
+        let lut = NearestInkLut::new(palette);
+        let wrong = (0..1u32 << 24)
+            .into_par_iter()
+            .map(|i| Rgb([(i >> 16) as u8, (i >> 8) as u8, i as u8]))
+            .filter(|&rgb| lut.nearest(rgb) != palette.nearest(rgb_to_oklab(rgb)))
+            .count();
+        assert_eq!(wrong, 0, "{} colours looked up wrongly", wrong);
+    }
+
+    #[test]
+    fn e6_palette_is_exact() {
+        assert_exact(&SPECTRA_6_13IN3.palette());
+    }
+
+    #[test]
+    fn acep_palette_is_exact() {
+        assert_exact(&ACEP_7IN3.palette());
+    }
+}
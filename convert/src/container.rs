@@ -0,0 +1,424 @@
+//! The frame file format.
+//!
+//! All numbers are little-endian:
+//!
+//! | bytes | content                                              |
+//! |-------|------------------------------------------------------|
+//! | 4     | magic `EPDF`                                         |
+//! | 1     | format version                                       |
+//! | 1     | pixel packing, see [`Packing`]                       |
+//! | 1     | compression, see [`Compression`] (version 2 onward) |
+//! | 2 + 2 | width and height in pixels                           |
+//! | 1 + n | palette id, UTF-8                                    |
+//! | 4 + n | source metadata as JSON, empty if there is none      |
+//! | 4 + n | packed pixels, compressed as given                   |
+//! | 4     | CRC32 of everything before it                        |
+//!
+//! Anything without the magic is read as a legacy raw dump of packed pixels.
+
+use crate::frame::{check_packed_len, EpdFrame, FrameError, PackedRows};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::{copy, sink, Error as IoError, ErrorKind, Read, Write};
+use std::str::FromStr;
+
+const MAGIC: &[u8; 4] = b"EPDF";
+pub const VERSION: u8 = 2;
+/// Version 1 had no compression byte and was always uncompressed.
+const UNCOMPRESSED_VERSION: u8 = 1;
+
+/// How pixels are laid out in the payload.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Packing {
+    /// Two pixels per byte, left one in the high nybble, rows top to bottom.
+    Nybbles = 0,
+}
+
+/// How the packed pixels are stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    /// Dithered frames shrink to a fraction of their size, and inflate row by row.
+    Deflate = 1,
+}
+
+impl TryFrom<u8> for Packing {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Packing::Nybbles),
+            _ => Err(FrameError::UnknownPacking(value)),
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(FrameError::UnknownCompression(value)),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Deflate => f.write_str("deflate"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(format!("Unknown compression: {}", s)),
+        }
+    }
+}
+
+/// Where a frame came from.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SourceMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    /// The image's EXIF description.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    /// When the photo was taken, from its EXIF, as `YYYY-MM-DD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured: Option<String>,
+    /// The source file and settings the frame was made from, see [`crate::fingerprint`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+/// A frame plus what the container records about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameFile {
+    pub frame: EpdFrame,
+    /// Name of the palette the frame was dithered with; unknown for legacy files.
+    pub palette: Option<String>,
+    pub source: Option<SourceMetadata>,
+}
+
+/// A verified frame file that hasn't been unpacked, so the pixels can be streamed out of
+/// the (possibly compressed) file contents without a second full copy.
+#[derive(Debug, Clone)]
+pub struct FrameView<'a> {
+    width: u32,
+    height: u32,
+    compression: Compression,
+    payload: &'a [u8],
+    pub palette: Option<String>,
+    pub source: Option<SourceMetadata>,
+}
+
+impl FrameFile {
+    pub fn new(frame: EpdFrame) -> Self {
+        Self {
+            frame,
+            palette: None,
+            source: None,
+        }
+    }
+
+    pub fn to_bytes(&self, compression: Compression) -> Vec<u8> {
+        let palette = self.palette.as_deref().unwrap_or_default().as_bytes();
+        let palette = &palette[..palette.len().min(u8::MAX as usize)];
+        let source = match &self.source {
+            Some(source) => serde_json::to_vec(source).expect("Source metadata always serializes"),
+            None => Vec::new(),
+        };
+        let pixels = match compression {
+            Compression::None => self.frame.as_bytes().to_vec(),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder
+                    .write_all(self.frame.as_bytes())
+                    .and_then(|_| encoder.finish())
+                    .expect("Deflating into memory can't fail")
+            }
+        };
+
+        let mut bytes = Vec::with_capacity(32 + palette.len() + source.len() + pixels.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(Packing::Nybbles as u8);
+        bytes.push(compression as u8);
+        bytes.extend_from_slice(&(self.frame.width() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.frame.height() as u16).to_le_bytes());
+        bytes.push(palette.len() as u8);
+        bytes.extend_from_slice(palette);
+        bytes.extend_from_slice(&(source.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&source);
+        bytes.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&pixels);
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Reads a container, or a legacy raw dump of a `legacy_width`×`legacy_height` frame.
+    pub fn from_bytes(bytes: &[u8], legacy_width: u32, legacy_height: u32) -> Result<Self, FrameError> {
+        let view = FrameView::new(bytes, legacy_width, legacy_height)?;
+        Ok(Self {
+            frame: view.unpack()?,
+            palette: view.palette,
+            source: view.source,
+        })
+    }
+}
+
+impl<'a> FrameView<'a> {
+    /// Checks the header and checksum of a container, or the size of a legacy raw dump of a
+    /// `legacy_width`×`legacy_height` frame.
+    pub fn new(bytes: &'a [u8], legacy_width: u32, legacy_height: u32) -> Result<Self, FrameError> {
+        if !bytes.starts_with(MAGIC) {
+            check_packed_len(legacy_width, legacy_height, bytes.len())?;
+            return Ok(Self {
+                width: legacy_width,
+                height: legacy_height,
+                compression: Compression::None,
+                payload: bytes,
+                palette: None,
+                source: None,
+            });
+        }
+
+        let mut reader = Reader { bytes };
+        reader.take(MAGIC.len())?;
+        let version = reader.u8()?;
+        if version != VERSION && version != UNCOMPRESSED_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let Packing::Nybbles = Packing::try_from(reader.u8()?)?;
+        let compression = match version {
+            UNCOMPRESSED_VERSION => Compression::None,
+            _ => Compression::try_from(reader.u8()?)?,
+        };
+        let width = reader.u16()? as u32;
+        let height = reader.u16()? as u32;
+        let palette_len = reader.u8()? as usize;
+        let palette = String::from_utf8_lossy(reader.take(palette_len)?).into_owned();
+        let source_len = reader.u32()? as usize;
+        let source = reader.take(source_len)?;
+        let payload_len = reader.u32()? as usize;
+        let payload = reader.take(payload_len)?;
+        let checked_len = bytes.len() - reader.bytes.len();
+        let expected = reader.u32()?;
+        let actual = crc32fast::hash(&bytes[..checked_len]);
+        if expected != actual {
+            return Err(FrameError::ChecksumMismatch { expected, actual });
+        }
+        let source = match source {
+            [] => None,
+            source => Some(serde_json::from_slice(source)?),
+        };
+        match compression {
+            Compression::None => check_packed_len(width, height, payload.len())?,
+            // inflated once up front, so a bad stream is caught before anything reaches the panel
+            Compression::Deflate => check_packed_len(width, height, inflated_len(payload)?)?,
+        }
+
+        Ok(Self {
+            width,
+            height,
+            compression,
+            payload,
+            palette: (!palette.is_empty()).then_some(palette),
+            source,
+        })
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Unpacks the whole frame into memory.
+    pub fn unpack(&self) -> Result<EpdFrame, FrameError> {
+        let mut data = Vec::with_capacity((self.width * self.height / 2) as usize);
+        self.for_each_row(&mut |row| data.extend_from_slice(row))?;
+        EpdFrame::from_packed(self.width, self.height, data)
+    }
+}
+
+impl PackedRows for FrameView<'_> {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn for_each_row(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), FrameError> {
+        let row_len = (self.width / 2) as usize;
+        match self.compression {
+            Compression::None => self.payload.chunks(row_len).for_each(f),
+            Compression::Deflate => {
+                let mut decoder = DeflateDecoder::new(self.payload);
+                let mut row = vec![0u8; row_len];
+                for _ in 0..self.height {
+                    decoder.read_exact(&mut row).map_err(truncated_or_io)?;
+                    f(&row);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn inflated_len(payload: &[u8]) -> Result<usize, FrameError> {
+    copy(&mut DeflateDecoder::new(payload), &mut sink())
+        .map(|len| len as usize)
+        .map_err(truncated_or_io)
+}
+
+fn truncated_or_io(err: IoError) -> FrameError {
+    match err.kind() {
+        ErrorKind::UnexpectedEof => FrameError::Truncated,
+        _ => FrameError::Io(err),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FrameError> {
+        if self.bytes.len() < len {
+            return Err(FrameError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, FrameError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FrameError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().expect("Took two bytes")))
+    }
+
+    fn u32(&mut self) -> Result<u32, FrameError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("Took four bytes")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::display_color::DisplayColor;
+
+    fn frame() -> EpdFrame {
+        EpdFrame::from_colors(4, 3, DisplayColor::ALL.into_iter().cycle().take(12))
+    }
+
+    /// A deflated container whose payload inflates to `packed`, however long that is.
+    fn deflated(width: u16, height: u16, packed: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(packed).unwrap();
+        let pixels = encoder.finish().unwrap();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION, Packing::Nybbles as u8, Compression::Deflate as u8]);
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(pixels.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&pixels);
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn frame_file() -> FrameFile {
+        FrameFile {
+            palette: Some("measured".to_string()),
+            source: Some(SourceMetadata {
+                file_name: Some("beach.jpg".to_string()),
+                width: 4000,
+                height: 3000,
+                ..SourceMetadata::default()
+            }),
+            ..FrameFile::new(frame())
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for compression in [Compression::None, Compression::Deflate] {
+            let bytes = frame_file().to_bytes(compression);
+            let view = FrameView::new(&bytes, 0, 0).unwrap();
+            assert_eq!(view.compression(), compression);
+            assert_eq!(FrameFile::from_bytes(&bytes, 0, 0).unwrap(), frame_file());
+        }
+    }
+
+    #[test]
+    fn reads_legacy_raw_frames() {
+        let read = FrameFile::from_bytes(frame().as_bytes(), 4, 3).unwrap();
+        assert_eq!(read, FrameFile::new(frame()));
+        assert!(matches!(
+            FrameFile::from_bytes(frame().as_bytes(), 4, 4),
+            Err(FrameError::WrongLength { expected: 8, actual: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn catches_corruption() {
+        let bytes = frame_file().to_bytes(Compression::Deflate);
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() - 6] ^= 0x01;
+        assert!(matches!(FrameView::new(&flipped, 0, 0), Err(FrameError::ChecksumMismatch { .. })));
+        for len in [MAGIC.len() + 3, bytes.len() / 2, bytes.len() - 1] {
+            assert!(matches!(FrameView::new(&bytes[..len], 0, 0), Err(FrameError::Truncated)), "cut to {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_headers() {
+        let mut bytes = frame_file().to_bytes(Compression::None);
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(FrameView::new(&bytes, 0, 0), Err(FrameError::UnsupportedVersion(_))));
+        let mut bytes = frame_file().to_bytes(Compression::None);
+        bytes[MAGIC.len() + 1] = 7;
+        assert!(matches!(FrameView::new(&bytes, 0, 0), Err(FrameError::UnknownPacking(7))));
+    }
+
+    #[test]
+    fn deflated_pixels_must_fill_the_frame_exactly() {
+        let packed = frame().into_bytes();
+        assert!(FrameView::new(&deflated(4, 3, &packed), 0, 0).is_ok());
+
+        let long = [packed.as_slice(), &[0x11]].concat();
+        assert!(matches!(
+            FrameView::new(&deflated(4, 3, &long), 0, 0),
+            Err(FrameError::WrongLength { expected: 6, actual: 7, .. })
+        ));
+        assert!(matches!(
+            FrameView::new(&deflated(4, 3, &packed[..5]), 0, 0),
+            Err(FrameError::WrongLength { expected: 6, actual: 5, .. })
+        ));
+    }
+}
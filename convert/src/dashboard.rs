@@ -0,0 +1,391 @@
+//! Information pages built from widgets, for schedule slots that shouldn't show a photo.
+//!
+//! A dashboard file (TOML, or JSON by extension) places widgets on the page as it hangs:
+//!
+//! ```toml
+//! accent = "red"
+//!
+//! [[widgets]]
+//! type = "clock"
+//! x = 0
+//! y = 0
+//! width = 1200
+//! height = 400
+//!
+//! [[widgets]]
+//! type = "list"
+//! title = "This week"
+//! items = ["Bins out Tuesday", "Piano Thursday"]
+//! x = 0
+//! y = 400
+//! width = 1200
+//! height = 600
+//! ```
+//!
+//! Relative image and weather paths are relative to the dashboard file.
+
+use crate::color::display_color::DisplayColor;
+use crate::color::e_paper_color_map::EPaperColorMap;
+use crate::color::ink_palette::InkPalette;
+use crate::color::rgb_to_display_nybbles;
+use crate::dither::{dither, DiffusionSpace, Dither};
+use crate::fit::{fit, FitMode};
+use crate::frame::EpdFrame;
+use crate::open_image;
+use crate::orientation::{orient, Mounting, Orientation};
+use crate::panel::PanelProfile;
+use crate::text::Text;
+use image::imageops::replace;
+use image::{DynamicImage, RgbImage};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+use serde::Deserialize;
+use std::fs;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use time::{Date, OffsetDateTime, PrimitiveDateTime};
+use tracing::warn;
+
+#[derive(Debug, Error)]
+pub enum DashboardError {
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("The {panel} panel has no {color} ink")]
+    MissingInk { color: DisplayColor, panel: &'static str },
+}
+
+/// What a widget shows.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Widget {
+    /// The time, big, with the date under it.
+    Clock,
+    /// This month's days, today picked out in the accent colour.
+    Calendar,
+    List {
+        #[serde(default)]
+        title: Option<String>,
+        items: Vec<String>,
+        /// Line height in pixels.
+        #[serde(default = "default_list_size")]
+        size: f32,
+    },
+    Image {
+        path: PathBuf,
+        #[serde(default)]
+        fit: FitMode,
+    },
+    /// The report in a [`WeatherReport`] JSON file, kept up to date by something else.
+    Weather { path: PathBuf },
+}
+
+/// A widget and the part of the page it takes up.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PlacedWidget {
+    #[serde(flatten)]
+    pub widget: Widget,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What a weather widget reads.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WeatherReport {
+    #[serde(default)]
+    pub location: Option<String>,
+    pub summary: String,
+    pub temperature: f32,
+    #[serde(default)]
+    pub high: Option<f32>,
+    #[serde(default)]
+    pub low: Option<f32>,
+    /// Printed after every temperature.
+    #[serde(default = "default_unit")]
+    pub unit: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Dashboard {
+    #[serde(default = "default_background")]
+    pub background: DisplayColor,
+    #[serde(default = "default_foreground")]
+    pub foreground: DisplayColor,
+    #[serde(default = "default_accent")]
+    pub accent: DisplayColor,
+    /// How image widgets are dithered.
+    #[serde(default)]
+    pub dither: Dither,
+    pub widgets: Vec<PlacedWidget>,
+    /// Where relative paths start from.
+    #[serde(skip)]
+    pub base: PathBuf,
+}
+
+fn default_list_size() -> f32 {
+    48.0
+}
+
+fn default_unit() -> String {
+    "°".to_string()
+}
+
+fn default_background() -> DisplayColor {
+    DisplayColor::White
+}
+
+fn default_foreground() -> DisplayColor {
+    DisplayColor::Black
+}
+
+fn default_accent() -> DisplayColor {
+    DisplayColor::Red
+}
+
+/// The colours a widget draws with, as they look in the palette.
+struct Colors {
+    background: image::Rgb<u8>,
+    foreground: image::Rgb<u8>,
+    accent: image::Rgb<u8>,
+}
+
+impl Dashboard {
+    pub fn load(path: &Path) -> Result<Self, DashboardError> {
+        let contents = fs::read_to_string(path)?;
+        let mut dashboard: Dashboard = if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        dashboard.base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(dashboard)
+    }
+
+    /// The page as it hangs: `panel`'s size, turned to match `mounting`.
+    pub fn render_page(
+        &self,
+        now: PrimitiveDateTime,
+        panel: &PanelProfile,
+        mounting: Mounting,
+        palette: &InkPalette,
+    ) -> Result<RgbImage, DashboardError> {
+        for color in [self.background, self.foreground, self.accent] {
+            if !panel.colors().any(|ink| ink == color) {
+                return Err(DashboardError::MissingInk { color, panel: panel.name });
+            }
+        }
+        let (long, short) = (panel.width.max(panel.height), panel.width.min(panel.height));
+        let (width, height) = if mounting.is_landscape() { (long, short) } else { (short, long) };
+        let colors = Colors {
+            background: palette.rgb(self.background),
+            foreground: palette.rgb(self.foreground),
+            accent: palette.rgb(self.accent),
+        };
+        let mut page = RgbImage::from_pixel(width, height, colors.background);
+        for placed in &self.widgets {
+            let mut tile = RgbImage::from_pixel(placed.width, placed.height, colors.background);
+            match &placed.widget {
+                Widget::Clock => draw_clock(&mut tile, now, &colors),
+                Widget::Calendar => draw_calendar(&mut tile, now.date(), &colors),
+                Widget::List { title, items, size } => draw_list(&mut tile, title.as_deref(), items, *size, &colors),
+                Widget::Image { path, fit } => self.draw_image(&mut tile, path, *fit, palette, &colors),
+                Widget::Weather { path } => self.draw_weather(&mut tile, path, &colors),
+            }
+            replace(&mut page, &tile, placed.x as i64, placed.y as i64);
+        }
+        Ok(page)
+    }
+
+    /// The page packed into a frame for `panel`.
+    pub fn render(
+        &self,
+        now: PrimitiveDateTime,
+        panel: &PanelProfile,
+        mounting: Mounting,
+        palette: &InkPalette,
+    ) -> Result<EpdFrame, DashboardError> {
+        let page = self.render_page(now, panel, mounting, palette)?;
+        Ok(pack_page(page, panel, mounting, palette))
+    }
+
+    fn draw_image(&self, tile: &mut RgbImage, path: &Path, fit_mode: FitMode, palette: &InkPalette, colors: &Colors) {
+        let img = match open_image(&self.base.join(path)) {
+            Ok(img) => img,
+            Err(err) => {
+                warn!("Can't show image {}: {}", path.display(), err);
+                return draw_message(tile, "Image unavailable", colors);
+            }
+        };
+        *tile = fit(&img, tile.width(), tile.height(), fit_mode, palette);
+        let color_map = EPaperColorMap::with_palette(palette.clone());
+        dither(tile, &color_map, self.dither, DiffusionSpace::default());
+    }
+
+    fn draw_weather(&self, tile: &mut RgbImage, path: &Path, colors: &Colors) {
+        let report = fs::read_to_string(self.base.join(path))
+            .map_err(DashboardError::from)
+            .and_then(|contents| Ok(serde_json::from_str::<WeatherReport>(&contents)?));
+        match report {
+            Ok(report) => draw_weather_report(tile, &report, colors),
+            Err(err) => {
+                warn!("Can't show weather from {}: {}", path.display(), err);
+                draw_message(tile, "Weather unavailable", colors);
+            }
+        }
+    }
+}
+
+/// The local wall-clock time, or UTC if the local offset can't be found.
+pub fn local_now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// Turns an upright page made by [`Dashboard::render_page`] into `panel`'s native layout and
+/// packs it. Every pixel must already be one of `palette`'s colours.
+pub fn pack_page(page: RgbImage, panel: &PanelProfile, mounting: Mounting, palette: &InkPalette) -> EpdFrame {
+    let layout = if mounting.is_landscape() { Orientation::Landscape } else { Orientation::Portrait };
+    let native = orient(DynamicImage::ImageRgb8(page), layout, mounting, panel).into_rgb8();
+    rgb_to_display_nybbles(&native, palette)
+}
+
+/// `size`, shrunk if need be so `line` fits in `width`.
+fn fitted(line: &str, size: f32, width: u32) -> Text {
+    let text = Text::new(size);
+    match text.width(line) {
+        natural if natural > width && natural > 0 => Text::new(size * width as f32 / natural as f32),
+        _ => text,
+    }
+}
+
+/// Draws `line` centred horizontally in `tile` with its top at `top`.
+fn draw_centered(tile: &mut RgbImage, text: &Text, line: &str, top: i32, rgb: image::Rgb<u8>) {
+    let left = (tile.width() as i32 - text.width(line) as i32) / 2;
+    text.draw(tile, line, left, top, rgb);
+}
+
+fn draw_message(tile: &mut RgbImage, message: &str, colors: &Colors) {
+    let text = fitted(message, 48.0, tile.width());
+    let top = (tile.height() as i32 - text.line_height() as i32) / 2;
+    draw_centered(tile, &text, message, top, colors.foreground);
+}
+
+fn draw_clock(tile: &mut RgbImage, now: PrimitiveDateTime, colors: &Colors) {
+    let time = format!("{:02}:{:02}", now.hour(), now.minute());
+    let date = format!("{} {} {} {}", now.weekday(), now.day(), now.month(), now.year());
+    let width = tile.width() * 9 / 10;
+    let time_text = fitted(&time, tile.height() as f32 * 0.6, width);
+    let date_text = fitted(&date, tile.height() as f32 * 0.15, width);
+    let top = (tile.height() as i32 - (time_text.line_height() + date_text.line_height()) as i32) / 2;
+    draw_centered(tile, &time_text, &time, top, colors.foreground);
+    draw_centered(tile, &date_text, &date, top + time_text.line_height() as i32, colors.accent);
+}
+
+/// Weeks start on Monday.
+fn draw_calendar(tile: &mut RgbImage, today: Date, colors: &Colors) {
+    let first = today.replace_day(1).expect("Every month has a first");
+    let offset = first.weekday().number_days_from_monday() as u32;
+    let days = today.month().length(today.year()) as u32;
+    let weeks = (offset + days).div_ceil(7);
+
+    let header = format!("{} {}", today.month(), today.year());
+    let header_text = fitted(&header, tile.height() as f32 / 9.0, tile.width());
+    draw_centered(tile, &header_text, &header, 0, colors.accent);
+    let grid_top = header_text.line_height();
+    let cell_width = tile.width() / 7;
+    let cell_height = tile.height().saturating_sub(grid_top) / (weeks + 1);
+    let text = Text::new(cell_height as f32 * 0.5);
+    let draw_cell = |tile: &mut RgbImage, column: u32, row: u32, label: &str, rgb: image::Rgb<u8>| {
+        let left = (column * cell_width + (cell_width.saturating_sub(text.width(label))) / 2) as i32;
+        let top = (grid_top + row * cell_height + cell_height.saturating_sub(text.line_height()) / 2) as i32;
+        text.draw(tile, label, left, top, rgb);
+    };
+    for (column, name) in ["Mo", "Tu", "We", "Th", "Fr", "Sa", "Su"].into_iter().enumerate() {
+        draw_cell(tile, column as u32, 0, name, colors.accent);
+    }
+    for day in 1..=days {
+        let cell = offset + day - 1;
+        let (column, row) = (cell % 7, cell / 7 + 1);
+        let mut rgb = colors.foreground;
+        if day == today.day() as u32 {
+            let rect = Rect::at((column * cell_width) as i32, (grid_top + row * cell_height) as i32)
+                .of_size(cell_width.max(1), cell_height.max(1));
+            draw_filled_rect_mut(tile, rect, colors.accent);
+            rgb = colors.background;
+        }
+        draw_cell(tile, column, row, &day.to_string(), rgb);
+    }
+}
+
+/// Items are word-wrapped; whatever doesn't fit is left off the bottom.
+fn draw_list(tile: &mut RgbImage, title: Option<&str>, items: &[String], size: f32, colors: &Colors) {
+    let text = Text::new(size);
+    let line_height = text.line_height() as i32;
+    let indent = text.width("• ");
+    let mut top = 0;
+    if let Some(title) = title {
+        fitted(title, size * 1.25, tile.width()).draw(tile, title, 0, top, colors.accent);
+        top += (line_height as f32 * 1.5) as i32;
+    }
+    for item in items {
+        if top + line_height > tile.height() as i32 {
+            break;
+        }
+        text.draw(tile, "•", 0, top, colors.foreground);
+        for line in wrap(&text, item, tile.width().saturating_sub(indent)) {
+            if top + line_height > tile.height() as i32 {
+                break;
+            }
+            text.draw(tile, &line, indent as i32, top, colors.foreground);
+            top += line_height;
+        }
+    }
+}
+
+/// Greedy word wrap; a single word wider than `width` gets a line to itself.
+fn wrap(text: &Text, paragraph: &str, width: u32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for word in paragraph.split_whitespace() {
+        match lines.last_mut() {
+            Some(line) if text.width(&format!("{} {}", line, word)) <= width => {
+                line.push(' ');
+                line.push_str(word);
+            }
+            _ => lines.push(word.to_string()),
+        }
+    }
+    lines
+}
+
+fn draw_weather_report(tile: &mut RgbImage, report: &WeatherReport, colors: &Colors) {
+    let height = tile.height() as f32;
+    let width = tile.width() * 9 / 10;
+    let temperature = format!("{:.0}{}", report.temperature, report.unit);
+    let range = match (report.high, report.low) {
+        (Some(high), Some(low)) => Some(format!("High {:.0}{unit}  Low {:.0}{unit}", high, low, unit = report.unit)),
+        (Some(high), None) => Some(format!("High {:.0}{}", high, report.unit)),
+        (None, Some(low)) => Some(format!("Low {:.0}{}", low, report.unit)),
+        (None, None) => None,
+    };
+    let mut lines = Vec::new();
+    if let Some(location) = &report.location {
+        lines.push((location.as_str(), fitted(location, height * 0.12, width), colors.accent));
+    }
+    lines.push((&temperature, fitted(&temperature, height * 0.4, width), colors.foreground));
+    lines.push((&report.summary, fitted(&report.summary, height * 0.14, width), colors.foreground));
+    if let Some(range) = &range {
+        lines.push((range, fitted(range, height * 0.12, width), colors.foreground));
+    }
+    let total: u32 = lines.iter().map(|(_, text, _)| text.line_height()).sum();
+    let mut top = (tile.height() as i32 - total as i32) / 2;
+    for (line, text, rgb) in lines {
+        draw_centered(tile, &text, line, top, rgb);
+        top += text.line_height() as i32;
+    }
+}
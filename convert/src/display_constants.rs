@@ -1,3 +0,0 @@
-pub const PIXEL_WIDTH: u32 = 1_200;
-pub const PIXEL_HEIGHT: u32 = 1_600;
-
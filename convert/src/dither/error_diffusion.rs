@@ -0,0 +1,557 @@
+use crate::color::display_color::{rgb_to_oklab, DisplayColor};
+use crate::color::e_paper_color_map::EPaperColorMap;
+use image::imageops::ColorMap;
+use image::{Rgb, RgbImage};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::ops::Add;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+
+/// The deepest kernel reaches two rows below the current one, so three rows of error are enough.
+const ERROR_ROWS: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DiffusionKernel {
+    #[default]
+    FloydSteinberg,
+    Atkinson,
+    JarvisJudiceNinke,
+    Stucki,
+    Sierra,
+    SierraLite,
+    Burkes,
+}
+
+impl DiffusionKernel {
+    pub const ALL: [DiffusionKernel; 7] = [
+        DiffusionKernel::FloydSteinberg,
+        DiffusionKernel::Atkinson,
+        DiffusionKernel::JarvisJudiceNinke,
+        DiffusionKernel::Stucki,
+        DiffusionKernel::Sierra,
+        DiffusionKernel::SierraLite,
+        DiffusionKernel::Burkes,
+    ];
+
+    /// `(dx, dy, weight)` for every neighbour the error is pushed onto.
+    fn weights(self) -> &'static [(i32, u32, i32)] {
+        match self {
+            DiffusionKernel::FloydSteinberg => &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+            DiffusionKernel::Atkinson => &[(1, 0, 1), (2, 0, 1), (-1, 1, 1), (0, 1, 1), (1, 1, 1), (0, 2, 1)],
+            DiffusionKernel::JarvisJudiceNinke => &[
+                (1, 0, 7),
+                (2, 0, 5),
+                (-2, 1, 3),
+                (-1, 1, 5),
+                (0, 1, 7),
+                (1, 1, 5),
+                (2, 1, 3),
+                (-2, 2, 1),
+                (-1, 2, 3),
+                (0, 2, 5),
+                (1, 2, 3),
+                (2, 2, 1),
+            ],
+            DiffusionKernel::Stucki => &[
+                (1, 0, 8),
+                (2, 0, 4),
+                (-2, 1, 2),
+                (-1, 1, 4),
+                (0, 1, 8),
+                (1, 1, 4),
+                (2, 1, 2),
+                (-2, 2, 1),
+                (-1, 2, 2),
+                (0, 2, 4),
+                (1, 2, 2),
+                (2, 2, 1),
+            ],
+            DiffusionKernel::Sierra => &[
+                (1, 0, 5),
+                (2, 0, 3),
+                (-2, 1, 2),
+                (-1, 1, 4),
+                (0, 1, 5),
+                (1, 1, 4),
+                (2, 1, 2),
+                (-1, 2, 2),
+                (0, 2, 3),
+                (1, 2, 2),
+            ],
+            DiffusionKernel::SierraLite => &[(1, 0, 2), (-1, 1, 1), (0, 1, 1)],
+            DiffusionKernel::Burkes => &[
+                (1, 0, 8),
+                (2, 0, 4),
+                (-2, 1, 2),
+                (-1, 1, 4),
+                (0, 1, 8),
+                (1, 1, 4),
+                (2, 1, 2),
+            ],
+        }
+    }
+
+    /// Atkinson deliberately only passes on 6/8 of the error, which keeps flat areas clean.
+    fn divisor(self) -> i32 {
+        match self {
+            DiffusionKernel::FloydSteinberg => 16,
+            DiffusionKernel::Atkinson => 8,
+            DiffusionKernel::JarvisJudiceNinke => 48,
+            DiffusionKernel::Stucki => 42,
+            DiffusionKernel::Sierra => 32,
+            DiffusionKernel::SierraLite => 4,
+            DiffusionKernel::Burkes => 32,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DiffusionKernel::FloydSteinberg => "floyd-steinberg",
+            DiffusionKernel::Atkinson => "atkinson",
+            DiffusionKernel::JarvisJudiceNinke => "jarvis-judice-ninke",
+            DiffusionKernel::Stucki => "stucki",
+            DiffusionKernel::Sierra => "sierra",
+            DiffusionKernel::SierraLite => "sierra-lite",
+            DiffusionKernel::Burkes => "burkes",
+        }
+    }
+}
+
+impl Display for DiffusionKernel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DiffusionKernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DiffusionKernel::ALL
+            .into_iter()
+            .find(|kernel| kernel.name() == s)
+            .ok_or_else(|| format!("Unknown diffusion kernel: {}", s))
+    }
+}
+
+/// The colour space quantization error is measured and spread in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DiffusionSpace {
+    /// Gamma-encoded u8 channels, like `image::imageops::dither`.
+    #[default]
+    Srgb,
+    /// Physically additive light, so mixes of ink average the way the eye sees them from afar.
+    LinearRgb,
+    /// The same space `EPaperColorMap` picks the nearest ink in.
+    Oklab,
+}
+
+impl DiffusionSpace {
+    pub const ALL: [DiffusionSpace; 3] = [DiffusionSpace::Srgb, DiffusionSpace::LinearRgb, DiffusionSpace::Oklab];
+
+    fn encode(self, rgb: Rgb<u8>) -> [f32; 3] {
+        match self {
+            DiffusionSpace::Srgb => rgb.0.map(|c| c as f32 / u8::MAX as f32),
+            DiffusionSpace::LinearRgb => {
+                let linear: LinSrgb = Srgb::new(rgb.0[0], rgb.0[1], rgb.0[2]).into_format::<f32>().into_linear();
+                [linear.red, linear.green, linear.blue]
+            }
+            DiffusionSpace::Oklab => {
+                let oklab = rgb_to_oklab(rgb);
+                [oklab.l, oklab.a, oklab.b]
+            }
+        }
+    }
+
+    fn encode_oklab(self, oklab: Oklab) -> [f32; 3] {
+        match self {
+            DiffusionSpace::Srgb => {
+                let srgb: Srgb = oklab.into_color();
+                [srgb.red, srgb.green, srgb.blue]
+            }
+            DiffusionSpace::LinearRgb => {
+                let linear: LinSrgb = oklab.into_color();
+                [linear.red, linear.green, linear.blue]
+            }
+            DiffusionSpace::Oklab => [oklab.l, oklab.a, oklab.b],
+        }
+    }
+
+    fn to_oklab(self, value: [f32; 3]) -> Oklab {
+        match self {
+            DiffusionSpace::Srgb => Srgb::new(value[0], value[1], value[2]).into_color(),
+            DiffusionSpace::LinearRgb => LinSrgb::new(value[0], value[1], value[2]).into_color(),
+            DiffusionSpace::Oklab => Oklab::new(value[0], value[1], value[2]),
+        }
+    }
+
+    /// Only for the RGB spaces, where the box the inks span lies inside the gamut.
+    fn to_rgb(self, value: [f32; 3]) -> Rgb<u8> {
+        let srgb: Srgb<u8> = match self {
+            DiffusionSpace::LinearRgb => Srgb::from_linear(LinSrgb::new(value[0], value[1], value[2])),
+            _ => Srgb::new(value[0], value[1], value[2]).into_format(),
+        };
+        Rgb([srgb.red, srgb.green, srgb.blue])
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DiffusionSpace::Srgb => "srgb",
+            DiffusionSpace::LinearRgb => "linear",
+            DiffusionSpace::Oklab => "oklab",
+        }
+    }
+}
+
+impl Display for DiffusionSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DiffusionSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DiffusionSpace::ALL
+            .into_iter()
+            .find(|space| space.name() == s)
+            .ok_or_else(|| format!("Unknown diffusion space: {}", s))
+    }
+}
+
+/// Quantizes `image` in place against `color_map`, spreading each pixel's error with `kernel`.
+///
+/// Error is accumulated at full precision (scaled by the kernel divisor) rather than being
+/// written back into the neighbouring u8 pixels, so it isn't lost to clamping along the way.
+pub fn diffuse<Map>(image: &mut RgbImage, color_map: &Map, kernel: DiffusionKernel)
+where
+    Map: ColorMap<Color = Rgb<u8>> + Sync + ?Sized,
+{
+    diffuse_on(image, color_map, kernel, threads())
+}
+
+fn diffuse_on<Map>(image: &mut RgbImage, color_map: &Map, kernel: DiffusionKernel, threads: usize)
+where
+    Map: ColorMap<Color = Rgb<u8>> + Sync + ?Sized,
+{
+    let divisor = kernel.divisor();
+    wavefront(image, kernel, threads, |pixel, accumulated: [i32; 3]| {
+        let wanted =
+            Rgb::from([0, 1, 2].map(|c| (pixel.0[c] as i32 + accumulated[c] / divisor).clamp(0, u8::MAX as i32) as u8));
+        let mut chosen = wanted;
+        color_map.map_color(&mut chosen);
+        (chosen, [0, 1, 2].map(|c| wanted.0[c] as i32 - chosen.0[c] as i32))
+    });
+}
+
+/// Like [`diffuse`], but keeps a floating-point error buffer in `space` rather than u8 sRGB.
+///
+/// With [`DiffusionSpace::Oklab`] the error is spread in the same space the nearest ink is
+/// chosen in, so the two agree; [`DiffusionSpace::LinearRgb`] spreads it as light, which
+/// stops dark areas from being crushed to black by gamma-encoded error.
+pub fn diffuse_in_space(image: &mut RgbImage, color_map: &EPaperColorMap, kernel: DiffusionKernel, space: DiffusionSpace) {
+    diffuse_in_space_on(image, color_map, kernel, space, threads())
+}
+
+fn diffuse_in_space_on(
+    image: &mut RgbImage,
+    color_map: &EPaperColorMap,
+    kernel: DiffusionKernel,
+    space: DiffusionSpace,
+    threads: usize,
+) {
+    let divisor = kernel.divisor() as f32;
+    let inks: Vec<(DisplayColor, [f32; 3])> = color_map
+        .colors()
+        .map(|(display_color, oklab)| (display_color, space.encode_oklab(oklab)))
+        .collect();
+    // Pixels are kept inside the box spanned by the inks, so error can't pile up chasing a
+    // colour no mix of inks will ever reach.
+    let low = [0, 1, 2].map(|c| inks.iter().map(|(_, ink)| ink[c]).fold(f32::INFINITY, f32::min));
+    let high = [0, 1, 2].map(|c| inks.iter().map(|(_, ink)| ink[c]).fold(f32::NEG_INFINITY, f32::max));
+
+    wavefront(image, kernel, threads, |pixel, accumulated: [f32; 3]| {
+        let source = space.encode(pixel);
+        let wanted = [0, 1, 2].map(|c| (source[c] + accumulated[c] / divisor).clamp(low[c], high[c]));
+        let chosen = match space {
+            DiffusionSpace::Srgb | DiffusionSpace::LinearRgb => color_map.nearest_rgb(space.to_rgb(wanted)),
+            // Oklab's box of inks reaches outside sRGB, where the lookup table doesn't go
+            DiffusionSpace::Oklab => color_map.nearest(space.to_oklab(wanted)),
+        };
+        let (_, ink) = inks
+            .iter()
+            .find(|(display_color, _)| *display_color == chosen)
+            .expect("Nearest colour is always in the map");
+        (color_map.palette().rgb(chosen), [0, 1, 2].map(|c| wanted[c] - ink[c]))
+    });
+}
+
+/// Threads to diffuse across. A rayon worker is already one of several conversions running side
+/// by side, and can't block on rows other workers might never get round to.
+fn threads() -> usize {
+    match rayon::current_thread_index() {
+        Some(_) => 1,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    }
+}
+
+/// A channel of quantization error, stored as bits so rows on other threads can read it.
+trait ErrorChannel: Copy + Default + Add<Output = Self> + Send + Sync {
+    fn weighted(self, weight: i32) -> Self;
+    fn to_bits(self) -> u32;
+    fn from_bits(bits: u32) -> Self;
+}
+
+impl ErrorChannel for i32 {
+    fn weighted(self, weight: i32) -> Self {
+        self * weight
+    }
+
+    fn to_bits(self) -> u32 {
+        self as u32
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        bits as i32
+    }
+}
+
+impl ErrorChannel for f32 {
+    fn weighted(self, weight: i32) -> Self {
+        self * weight as f32
+    }
+
+    fn to_bits(self) -> u32 {
+        f32::to_bits(self)
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        f32::from_bits(bits)
+    }
+}
+
+/// Each pixel's own error, kept for the rows below, and how far along each row has got.
+struct ErrorRows {
+    width: usize,
+    errors: Vec<[AtomicU32; 3]>,
+    progress: Vec<AtomicU32>,
+}
+
+impl ErrorRows {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            errors: (0..width * ERROR_ROWS).map(|_| Default::default()).collect(),
+            progress: (0..height).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    fn cell(&self, x: usize, y: usize) -> &[AtomicU32; 3] {
+        &self.errors[(y % ERROR_ROWS) * self.width + x]
+    }
+
+    fn get<E: ErrorChannel>(&self, x: usize, y: usize) -> [E; 3] {
+        self.cell(x, y).each_ref().map(|c| E::from_bits(c.load(Ordering::Relaxed)))
+    }
+
+    fn set<E: ErrorChannel>(&self, x: usize, y: usize, error: [E; 3]) {
+        for (cell, error) in self.cell(x, y).iter().zip(error) {
+            cell.store(error.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Marks the first `done` pixels of row `y` as having their error in place.
+    fn advance(&self, y: usize, done: usize) {
+        self.progress[y].store(done as u32, Ordering::Release);
+    }
+
+    /// Waits for row `y` to get `done` pixels in, returning how many it actually has.
+    fn wait_for(&self, y: usize, done: usize) -> usize {
+        let mut spins = 0;
+        loop {
+            let progress = self.progress[y].load(Ordering::Acquire) as usize;
+            if progress >= done {
+                return progress;
+            }
+            if spins < 64 {
+                spins += 1;
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Runs error diffusion over `image`, `quantize` turning each pixel plus the error it
+/// inherited (still scaled by the divisor) into its ink and the error it leaves.
+///
+/// Rather than pushing its error onto the neighbours, every pixel pulls from those that would
+/// have pushed onto it, in the order they would have. That keeps the sums, float rounding
+/// included, exactly as a single pass would make them, while rows go round-robin to threads
+/// that each trail the row above by a few pixels.
+fn wavefront<E, Quantize>(image: &mut RgbImage, kernel: DiffusionKernel, threads: usize, quantize: Quantize)
+where
+    E: ErrorChannel,
+    Quantize: Fn(Rgb<u8>, [E; 3]) -> (Rgb<u8>, [E; 3]) + Sync,
+{
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+    // pushed in raster order means pulled from the furthest back first
+    let mut sources: Vec<(i32, usize, i32)> =
+        kernel.weights().iter().map(|&(dx, dy, weight)| (dx, dy as usize, weight)).collect();
+    sources.sort_by_key(|&(dx, dy, _)| (Reverse(dy), Reverse(dx)));
+    // past the furthest pixel of the row above any pixel pulls from
+    let reach = sources
+        .iter()
+        .filter(|&&(_, dy, _)| dy > 0)
+        .map(|&(dx, _, _)| (-dx).max(0) as usize)
+        .max()
+        .unwrap_or(0);
+    let lag = reach + 1;
+
+    let rows = ErrorRows::new(width, height);
+    let diffuse_row = |y: usize, row: &mut [u8]| {
+        let mut ready = if y == 0 { width } else { 0 };
+        for x in 0..width {
+            if ready < (x + lag).min(width) {
+                ready = rows.wait_for(y - 1, (x + lag).min(width));
+            }
+            let mut accumulated = [E::default(); 3];
+            for &(dx, dy, weight) in &sources {
+                let source_x = x as i64 - dx as i64;
+                if source_x < 0 || source_x >= width as i64 || dy > y {
+                    continue;
+                }
+                let error = rows.get::<E>(source_x as usize, y - dy);
+                for c in 0..3 {
+                    accumulated[c] = accumulated[c] + error[c].weighted(weight);
+                }
+            }
+            let pixel = &mut row[x * 3..x * 3 + 3];
+            let (chosen, error) = quantize(Rgb([pixel[0], pixel[1], pixel[2]]), accumulated);
+            pixel.copy_from_slice(&chosen.0);
+            rows.set(x, y, error);
+            rows.advance(y, x + 1);
+        }
+    };
+
+    let threads = threads.clamp(1, height);
+    let mut shares: Vec<Vec<(usize, &mut [u8])>> = (0..threads).map(|_| Vec::new()).collect();
+    for (y, row) in image.chunks_exact_mut(width * 3).enumerate() {
+        shares[y % threads].push((y, row));
+    }
+    let diffuse_row = &diffuse_row;
+    thread::scope(|scope| {
+        for share in shares {
+            scope.spawn(move || {
+                for (y, row) in share {
+                    diffuse_row(y, row);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smooth gradients with fine noise over them, so every kernel has error to carry.
+    fn picture() -> RgbImage {
+        RgbImage::from_fn(97, 61, |x, y| {
+            let noise = (x.wrapping_mul(0x9E37_79B1) ^ y.wrapping_mul(0x85EB_CA77)) >> 27;
+            Rgb([(x * 255 / 96) as u8, (y * 255 / 60) as u8, ((x + y) * 2 + noise) as u8])
+        })
+    }
+
+    #[test]
+    fn kernels_pass_on_all_their_error_forwards() {
+        for kernel in DiffusionKernel::ALL {
+            let weights = kernel.weights();
+            let total: i32 = weights.iter().map(|&(_, _, weight)| weight).sum();
+            match kernel {
+                DiffusionKernel::Atkinson => assert_eq!(total * 8, kernel.divisor() * 6),
+                _ => assert_eq!(total, kernel.divisor(), "{}", kernel),
+            }
+            for &(dx, dy, weight) in weights {
+                assert!(weight > 0, "{}", kernel);
+                assert!(dy > 0 || dx > 0, "{} pushes back onto ({}, {})", kernel, dx, dy);
+                assert!((dy as usize) < ERROR_ROWS, "{}", kernel);
+            }
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for kernel in DiffusionKernel::ALL {
+            assert_eq!(kernel.to_string().parse(), Ok(kernel));
+        }
+        for space in DiffusionSpace::ALL {
+            assert_eq!(space.to_string().parse(), Ok(space));
+        }
+        assert!("floyd".parse::<DiffusionKernel>().is_err());
+    }
+
+    #[test]
+    fn spaces_convert_back_to_the_same_colour() {
+        for space in DiffusionSpace::ALL {
+            for rgb in [[0, 0, 0], [255, 255, 255], [128, 128, 128], [200, 30, 90], [12, 240, 7]].map(Rgb) {
+                let oklab = space.to_oklab(space.encode(rgb));
+                let expected = rgb_to_oklab(rgb);
+                for (got, expected) in [(oklab.l, expected.l), (oklab.a, expected.a), (oklab.b, expected.b)] {
+                    assert!((got - expected).abs() < 1e-4, "{} {:?}", space, rgb);
+                }
+                for (from_oklab, encoded) in space.encode_oklab(expected).into_iter().zip(space.encode(rgb)) {
+                    assert!((from_oklab - encoded).abs() < 1e-4, "{} {:?}", space, rgb);
+                }
+                if space != DiffusionSpace::Oklab {
+                    assert_eq!(space.to_rgb(space.encode(rgb)), rgb, "{}", space);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn linear_light_is_darker_than_srgb_values() {
+        // sRGB mid-grey is about a fifth of the light of white
+        let [red, _, _] = DiffusionSpace::LinearRgb.encode(Rgb([128, 128, 128]));
+        assert!((red - 0.2159).abs() < 1e-3, "{}", red);
+    }
+
+    #[test]
+    fn threads_match_a_single_pass() {
+        let color_map = EPaperColorMap::new();
+        for kernel in DiffusionKernel::ALL {
+            let mut serial = picture();
+            diffuse_on(&mut serial, &color_map, kernel, 1);
+            for threads in [2, 3, 4, 7] {
+                let mut threaded = picture();
+                diffuse_on(&mut threaded, &color_map, kernel, threads);
+                assert_eq!(serial, threaded, "{} on {} threads", kernel, threads);
+            }
+        }
+    }
+
+    #[test]
+    fn threads_match_a_single_pass_in_every_space() {
+        let color_map = EPaperColorMap::new();
+        for space in DiffusionSpace::ALL {
+            for kernel in DiffusionKernel::ALL {
+                let mut serial = picture();
+                diffuse_in_space_on(&mut serial, &color_map, kernel, space, 1);
+                for threads in [2, 3, 4, 7] {
+                    let mut threaded = picture();
+                    diffuse_in_space_on(&mut threaded, &color_map, kernel, space, threads);
+                    assert_eq!(serial, threaded, "{} in {} on {} threads", kernel, space, threads);
+                }
+            }
+        }
+    }
+}
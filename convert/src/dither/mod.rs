@@ -0,0 +1,81 @@
+pub mod error_diffusion;
+pub mod ordered;
+
+pub use error_diffusion::{diffuse, diffuse_in_space, DiffusionKernel, DiffusionSpace};
+pub use ordered::{ordered, ThresholdMap};
+
+use crate::color::e_paper_color_map::EPaperColorMap;
+use image::imageops::ColorMap;
+use image::RgbImage;
+use rayon::iter::ParallelIterator;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Dither {
+    /// Every pixel just takes its nearest ink; flat artwork stays clean, photos band.
+    None,
+    ErrorDiffusion(DiffusionKernel),
+    Ordered(ThresholdMap),
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Dither::ErrorDiffusion(DiffusionKernel::default())
+    }
+}
+
+impl Display for Dither {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dither::None => f.write_str("none"),
+            Dither::ErrorDiffusion(kernel) => kernel.fmt(f),
+            Dither::Ordered(threshold_map) => threshold_map.fmt(f),
+        }
+    }
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Dither::None);
+        }
+        s.parse()
+            .map(Dither::ErrorDiffusion)
+            .or_else(|_| s.parse().map(Dither::Ordered))
+            .map_err(|_: String| format!("Unknown dither method: {}", s))
+    }
+}
+
+impl TryFrom<String> for Dither {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Dither> for String {
+    fn from(value: Dither) -> Self {
+        value.to_string()
+    }
+}
+
+/// `space` only applies to error diffusion; ordered dithering carries no error between pixels.
+pub fn dither(image: &mut RgbImage, color_map: &EPaperColorMap, method: Dither, space: DiffusionSpace) {
+    match method {
+        Dither::None => quantize(image, color_map),
+        Dither::ErrorDiffusion(kernel) if space == DiffusionSpace::Srgb => diffuse(image, color_map, kernel),
+        Dither::ErrorDiffusion(kernel) => diffuse_in_space(image, color_map, kernel, space),
+        Dither::Ordered(threshold_map) => ordered(image, color_map, threshold_map),
+    }
+}
+
+/// Maps every pixel to its nearest ink, carrying nothing over to its neighbours.
+pub fn quantize(image: &mut RgbImage, color_map: &EPaperColorMap) {
+    image.par_pixels_mut().for_each(|pixel| color_map.map_color(pixel));
+}
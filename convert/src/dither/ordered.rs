@@ -0,0 +1,161 @@
+use crate::color::display_color::rgb_to_oklab;
+use crate::color::e_paper_color_map::EPaperColorMap;
+use image::RgbImage;
+use palette::Oklab;
+use rayon::iter::ParallelIterator;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// 64×64 void-and-cluster tile, one threshold rank per byte (each of the 256 levels appears 16 times).
+const BLUE_NOISE_64: &[u8; 64 * 64] = include_bytes!("blue_noise_64.bin");
+const BLUE_NOISE_SIZE: usize = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThresholdMap {
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    BlueNoise,
+}
+
+impl ThresholdMap {
+    pub const ALL: [ThresholdMap; 4] = [
+        ThresholdMap::Bayer2,
+        ThresholdMap::Bayer4,
+        ThresholdMap::Bayer8,
+        ThresholdMap::BlueNoise,
+    ];
+
+    fn size(self) -> usize {
+        match self {
+            ThresholdMap::Bayer2 => 2,
+            ThresholdMap::Bayer4 => 4,
+            ThresholdMap::Bayer8 => 8,
+            ThresholdMap::BlueNoise => BLUE_NOISE_SIZE,
+        }
+    }
+
+    /// Row-major thresholds in `(0, 1)`.
+    fn thresholds(self) -> Vec<f32> {
+        match self {
+            ThresholdMap::BlueNoise => BLUE_NOISE_64.iter().map(|&t| (t as f32 + 0.5) / 256.0).collect(),
+            _ => {
+                let size = self.size();
+                let levels = (size * size) as f32;
+                (0..size * size)
+                    .map(|i| (bayer_rank(i % size, i / size, size) as f32 + 0.5) / levels)
+                    .collect()
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ThresholdMap::Bayer2 => "bayer-2",
+            ThresholdMap::Bayer4 => "bayer-4",
+            ThresholdMap::Bayer8 => "bayer-8",
+            ThresholdMap::BlueNoise => "blue-noise",
+        }
+    }
+}
+
+impl Display for ThresholdMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ThresholdMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ThresholdMap::ALL
+            .into_iter()
+            .find(|map| map.name() == s)
+            .ok_or_else(|| format!("Unknown threshold map: {}", s))
+    }
+}
+
+/// Position of `(x, y)` in the recursive Bayer ordering of a `size`×`size` matrix (size a power of two).
+fn bayer_rank(x: usize, y: usize, size: usize) -> usize {
+    // the finest bit of the position is the most significant digit of the rank
+    let mut rank = 0;
+    let mut bit = 1;
+    while bit < size {
+        let quadrant = match (x & bit != 0, y & bit != 0) {
+            (false, false) => 0,
+            (true, true) => 1,
+            (true, false) => 2,
+            (false, true) => 3,
+        };
+        rank = rank * 4 + quadrant;
+        bit *= 2;
+    }
+    rank
+}
+
+/// How far along the line from `from` to `to` the projection of `color` lies, clamped to `[0, 1]`.
+fn mix_ratio(color: Oklab, from: Oklab, to: Oklab) -> f32 {
+    let axis = [to.l - from.l, to.a - from.a, to.b - from.b];
+    let offset = [color.l - from.l, color.a - from.a, color.b - from.b];
+    let length_squared: f32 = axis.iter().map(|v| v * v).sum();
+    if length_squared == 0.0 {
+        return 0.0;
+    }
+    let dot: f32 = axis.iter().zip(offset).map(|(a, o)| a * o).sum();
+    (dot / length_squared).clamp(0.0, 1.0)
+}
+
+/// Quantizes `image` in place against `color_map`. Each pixel is treated as a mix of its two
+/// nearest inks, and the tiled threshold decides which of the two it gets. No state carries
+/// between pixels, so the pattern for a given input is stable.
+pub fn ordered(image: &mut RgbImage, color_map: &EPaperColorMap, threshold_map: ThresholdMap) {
+    let size = threshold_map.size();
+    let thresholds = threshold_map.thresholds();
+    image.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        let threshold = thresholds[(y as usize % size) * size + x as usize % size];
+        let color = rgb_to_oklab(*pixel);
+        let [(nearest, nearest_lab), (second, second_lab)] = color_map.nearest_pair(color);
+        let chosen = if threshold < mix_ratio(color, nearest_lab, second_lab) {
+            second
+        } else {
+            nearest
+        };
+        *pixel = color_map.palette().rgb(chosen);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_matrices_are_the_standard_ones() {
+        let ranks = |size: usize| (0..size * size).map(|i| bayer_rank(i % size, i / size, size)).collect::<Vec<_>>();
+        assert_eq!(ranks(2), [0, 2, 3, 1]);
+        assert_eq!(ranks(4), [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5]);
+        for size in [2, 4, 8] {
+            let mut sorted = ranks(size);
+            sorted.sort_unstable();
+            assert_eq!(sorted, (0..size * size).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn thresholds_sit_strictly_inside_0_to_1() {
+        for map in ThresholdMap::ALL {
+            let thresholds = map.thresholds();
+            assert_eq!(thresholds.len(), map.size() * map.size());
+            assert!(thresholds.iter().all(|&t| t > 0.0 && t < 1.0), "{}", map);
+            let mean = thresholds.iter().sum::<f32>() / thresholds.len() as f32;
+            assert!((mean - 0.5).abs() < 0.01, "{} averages {}", map, mean);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for map in ThresholdMap::ALL {
+            assert_eq!(map.to_string().parse(), Ok(map));
+        }
+    }
+}
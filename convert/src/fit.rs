@@ -0,0 +1,176 @@
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use fast_image_resize::{FilterType, ResizeAlg, ResizeOptions, Resizer};
+use image::imageops::{blur, overlay};
+use image::{DynamicImage, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The blurred background is built at this fraction of the frame size; it's going to be a blur anyway.
+const BLUR_DOWNSCALE: u32 = 8;
+const BLUR_SIGMA: f32 = 4.0;
+
+/// What fills the frame around an image that doesn't cover it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Matting {
+    Color(DisplayColor),
+    /// A blurred, stretched copy of the image itself.
+    Blur,
+}
+
+/// How an image is made to fit the frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FitMode {
+    /// Scale to cover the whole frame, cropping whatever sticks out.
+    #[default]
+    Fill,
+    /// Scale to fit entirely inside the frame, matting the rest.
+    Contain(Matting),
+    /// Scale each axis to the frame independently, distorting the aspect ratio.
+    Stretch,
+    /// Keep the original size, centred, cropping or matting as needed.
+    Center(Matting),
+}
+
+impl Display for Matting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Matting::Color(display_color) => display_color.fmt(f),
+            Matting::Blur => f.write_str("blur"),
+        }
+    }
+}
+
+impl FromStr for Matting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "blur" {
+            return Ok(Matting::Blur);
+        }
+        s.parse()
+            .map(Matting::Color)
+            .map_err(|_| format!("Unknown matting: {}", s))
+    }
+}
+
+impl Display for FitMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FitMode::Fill => f.write_str("fill"),
+            FitMode::Contain(matting) => write!(f, "contain:{}", matting),
+            FitMode::Stretch => f.write_str("stretch"),
+            FitMode::Center(matting) => write!(f, "center:{}", matting),
+        }
+    }
+}
+
+/// `fill`, `stretch`, or `contain`/`center` optionally followed by `:<matting>`,
+/// where matting is `blur` or a colour name (white when left out).
+impl FromStr for FitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, matting) = match s.split_once(':') {
+            Some((mode, matting)) => (mode, matting.parse()?),
+            None => (s, Matting::Color(DisplayColor::White)),
+        };
+        match mode {
+            "fill" => Ok(FitMode::Fill),
+            "contain" => Ok(FitMode::Contain(matting)),
+            "stretch" => Ok(FitMode::Stretch),
+            "center" => Ok(FitMode::Center(matting)),
+            _ => Err(format!("Unknown fit mode: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FitMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<FitMode> for String {
+    fn from(value: FitMode) -> Self {
+        value.to_string()
+    }
+}
+
+pub(crate) fn matte(img: &DynamicImage, width: u32, height: u32, matting: Matting, palette: &InkPalette) -> RgbImage {
+    match matting {
+        Matting::Color(display_color) => RgbImage::from_pixel(width, height, palette.rgb(display_color)),
+        Matting::Blur => {
+            let small = resize(
+                img,
+                (width / BLUR_DOWNSCALE).max(1),
+                (height / BLUR_DOWNSCALE).max(1),
+                FilterType::Bilinear,
+                true,
+            );
+            let blurred = DynamicImage::ImageRgb8(blur(&small, BLUR_SIGMA));
+            resize(&blurred, width, height, FilterType::Bilinear, false)
+        }
+    }
+}
+
+/// Resamples `img` to exactly `width`×`height`, cropping it to that aspect ratio first if `crop`
+/// is set. Rows are split across threads, which the image crate's resize doesn't do.
+fn resize(img: &DynamicImage, width: u32, height: u32, filter: FilterType, crop: bool) -> RgbImage {
+    let src = match img {
+        DynamicImage::ImageRgb8(rgb) => Cow::Borrowed(rgb),
+        _ => Cow::Owned(img.to_rgb8()),
+    };
+    let mut options = ResizeOptions::new().resize_alg(ResizeAlg::Convolution(filter));
+    if crop {
+        options = options.fit_into_destination(None);
+    }
+    let mut dst = RgbImage::new(width, height);
+    // the resizer won't take an empty image either side
+    if dst.is_empty() || src.is_empty() {
+        return dst;
+    }
+    Resizer::new()
+        .resize(src.as_ref(), &mut dst, &options)
+        .expect("Both images are 8-bit RGB");
+    dst
+}
+
+/// The largest size with `img`'s aspect ratio that fits inside `width`×`height`.
+fn contained_size(img: &DynamicImage, width: u32, height: u32) -> (u32, u32) {
+    let ratio = f64::min(width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+    let scaled = |side: u32, bound: u32| ((side as f64 * ratio).round() as u32).clamp(1, bound);
+    (scaled(img.width(), width), scaled(img.height(), height))
+}
+
+fn centered_on(background: RgbImage, img: &RgbImage) -> RgbImage {
+    let mut canvas = background;
+    let x = (canvas.width() as i64 - img.width() as i64) / 2;
+    let y = (canvas.height() as i64 - img.height() as i64) / 2;
+    overlay(&mut canvas, img, x, y);
+    canvas
+}
+
+/// Brings `img` to exactly `width`×`height` according to `mode`. Coloured matting is drawn with
+/// the palette's own colour for that ink, so it dithers to a solid area.
+pub fn fit(img: &DynamicImage, width: u32, height: u32, mode: FitMode, palette: &InkPalette) -> RgbImage {
+    if width == 0 || height == 0 {
+        return RgbImage::new(width, height);
+    }
+    match mode {
+        FitMode::Fill => resize(img, width, height, FilterType::Lanczos3, true),
+        FitMode::Stretch => resize(img, width, height, FilterType::Lanczos3, false),
+        FitMode::Contain(matting) => {
+            let (scaled_width, scaled_height) = contained_size(img, width, height);
+            let scaled = resize(img, scaled_width, scaled_height, FilterType::Lanczos3, false);
+            centered_on(matte(img, width, height, matting, palette), &scaled)
+        }
+        FitMode::Center(matting) => centered_on(matte(img, width, height, matting, palette), &img.to_rgb8()),
+    }
+}
+
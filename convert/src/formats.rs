@@ -0,0 +1,147 @@
+//! Photo formats that only decode with one of this crate's cargo features turned on.
+
+use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{DynamicImage, ImageError};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PhotoFormat {
+    /// What iPhones take pictures in.
+    Heic,
+    Avif,
+    WebP,
+    JpegXl,
+}
+
+impl PhotoFormat {
+    pub const ALL: [PhotoFormat; 4] = [PhotoFormat::Heic, PhotoFormat::Avif, PhotoFormat::WebP, PhotoFormat::JpegXl];
+
+    fn name(self) -> &'static str {
+        match self {
+            PhotoFormat::Heic => "HEIC",
+            PhotoFormat::Avif => "AVIF",
+            PhotoFormat::WebP => "WebP",
+            PhotoFormat::JpegXl => "JPEG XL",
+        }
+    }
+
+    /// The cargo feature that decodes it.
+    pub fn feature(self) -> &'static str {
+        match self {
+            PhotoFormat::Heic => "heic",
+            PhotoFormat::Avif => "avif",
+            PhotoFormat::WebP => "webp",
+            PhotoFormat::JpegXl => "jxl",
+        }
+    }
+
+    pub fn is_compiled_in(self) -> bool {
+        match self {
+            PhotoFormat::Heic => cfg!(feature = "heic"),
+            PhotoFormat::Avif => cfg!(feature = "avif"),
+            PhotoFormat::WebP => cfg!(feature = "webp"),
+            PhotoFormat::JpegXl => cfg!(feature = "jxl"),
+        }
+    }
+
+    /// Tells the format from the file's first few bytes; nothing for anything else.
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0x0A]) || bytes.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n") {
+            return Some(PhotoFormat::JpegXl);
+        }
+        if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+            return Some(PhotoFormat::WebP);
+        }
+        if bytes.get(4..8) != Some(b"ftyp") {
+            return None;
+        }
+        // the major brand, then the compatible ones; AVIFs often claim the generic mif1 first
+        let size = u32::from_be_bytes(bytes[..4].try_into().expect("4 bytes")) as usize;
+        let brands: Vec<&[u8]> = bytes[8..size.min(bytes.len()).max(8)]
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(i, _)| *i != 1) // minor version
+            .map(|(_, brand)| brand)
+            .collect();
+        if brands.iter().any(|brand| matches!(*brand, b"avif" | b"avis")) {
+            Some(PhotoFormat::Avif)
+        } else if brands
+            .iter()
+            .any(|brand| matches!(*brand, b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1"))
+        {
+            Some(PhotoFormat::Heic)
+        } else {
+            None
+        }
+    }
+
+    /// The error for a file in this format when its feature isn't compiled in.
+    pub fn unsupported(self) -> ImageError {
+        let hint = ImageFormatHint::Name(format!("{} (needs eink-convert's {} feature)", self, self.feature()));
+        ImageError::Unsupported(UnsupportedError::from_format_and_kind(hint.clone(), UnsupportedErrorKind::Format(hint)))
+    }
+}
+
+impl Display for PhotoFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Fails with [`PhotoFormat::unsupported`] for a file that needs a feature this build lacks.
+pub fn check_supported(bytes: &[u8]) -> Result<(), ImageError> {
+    match PhotoFormat::sniff(bytes) {
+        Some(format) if !format.is_compiled_in() => Err(format.unsupported()),
+        _ => Ok(()),
+    }
+}
+
+/// Decodes `bytes` if they need decoding here rather than by the `image` crate.
+pub(crate) fn decode(bytes: &[u8]) -> Option<Result<DynamicImage, ImageError>> {
+    let format = PhotoFormat::sniff(bytes)?;
+    if !format.is_compiled_in() {
+        return Some(Err(format.unsupported()));
+    }
+    match format {
+        #[cfg(feature = "heic")]
+        PhotoFormat::Heic => Some(decode_heic(bytes)),
+        #[cfg(feature = "jxl")]
+        PhotoFormat::JpegXl => Some(decode_jxl(bytes)),
+        _ => None,
+    }
+}
+
+/// Comes out already rotated and cropped as the file asks.
+#[cfg(feature = "heic")]
+fn decode_heic(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    use image::RgbImage;
+    use image::error::DecodingError;
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let decoding = |err: libheif_rs::HeifError| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name(PhotoFormat::Heic.to_string()), err))
+    };
+    let context = HeifContext::read_from_bytes(bytes).map_err(decoding)?;
+    let handle = context.primary_image_handle().map_err(decoding)?;
+    let decoded = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+        .map_err(decoding)?;
+    let plane = decoded.planes().interleaved.expect("Interleaved RGB has one plane");
+    let row_bytes = plane.width as usize * 3;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_bytes])
+        .copied()
+        .collect();
+    let img = RgbImage::from_raw(plane.width, plane.height, pixels).expect("Rows are width × 3 bytes");
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+/// Comes out already oriented as the file asks.
+#[cfg(feature = "jxl")]
+fn decode_jxl(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    let decoder = jxl_oxide::integration::JxlDecoder::new(std::io::Cursor::new(bytes))?;
+    DynamicImage::from_decoder(decoder)
+}
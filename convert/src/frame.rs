@@ -0,0 +1,240 @@
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use crate::color::nearest_lut::NearestInkLut;
+use crate::panel::PanelProfile;
+use image::{Pixel, Rgb, RgbImage};
+use rayon::prelude::*;
+use std::io::Error as IoError;
+use thiserror::Error;
+
+/// Stands out against every ink, so invalid nybbles are easy to spot in a render.
+pub const INVALID_NYBBLE_RGB: Rgb<u8> = Rgb([255, 0, 255]);
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Frame file ends early")]
+    Truncated,
+    #[error("Frame file checksum is {actual:#010x}, expected {expected:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("Frame file version {0} is not supported")]
+    UnsupportedVersion(u8),
+    #[error("Unknown pixel packing {0}")]
+    UnknownPacking(u8),
+    #[error("Unknown frame compression {0}")]
+    UnknownCompression(u8),
+    #[error("Frame width {0} is odd, but pixels are packed two per byte")]
+    OddWidth(u32),
+    #[error("A {width}x{height} frame takes {expected} packed bytes, got {actual}")]
+    WrongLength {
+        width: u32,
+        height: u32,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Occurrences of a nybble that isn't one of the panel's inks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidNybbles {
+    pub nybble: u8,
+    pub count: usize,
+    /// `(x, y)` of the first one, row by row.
+    pub first: (u32, u32),
+}
+
+/// Packed pixels that can be handed out a row at a time, e.g. to stream them to the panel.
+pub trait PackedRows {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// Calls `f` with every row's packed bytes, top to bottom.
+    fn for_each_row(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), FrameError>;
+}
+
+pub(crate) fn check_packed_len(width: u32, height: u32, len: usize) -> Result<(), FrameError> {
+    if !width.is_multiple_of(2) {
+        return Err(FrameError::OddWidth(width));
+    }
+    let expected = (width * height / 2) as usize;
+    if len != expected {
+        return Err(FrameError::WrongLength {
+            width,
+            height,
+            expected,
+            actual: len,
+        });
+    }
+    Ok(())
+}
+
+/// A frame packed the way the panel takes it: two pixels per byte, left one in the high nybble,
+/// rows top to bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpdFrame {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl EpdFrame {
+    /// A frame of a single colour. Panics if `width` is odd.
+    pub fn new(width: u32, height: u32, color: DisplayColor) -> Self {
+        assert!(width.is_multiple_of(2), "Frame width must be even to pack two pixels per byte");
+        let byte = u8::from(color) << 4 | u8::from(color);
+        Self {
+            width,
+            height,
+            data: vec![byte; (width * height / 2) as usize],
+        }
+    }
+
+    pub fn from_packed(width: u32, height: u32, data: Vec<u8>) -> Result<Self, FrameError> {
+        check_packed_len(width, height, data.len())?;
+        Ok(Self { width, height, data })
+    }
+
+    /// Packs `colors`, given row by row. Panics if there aren't exactly `width`×`height` of them.
+    pub fn from_colors(width: u32, height: u32, colors: impl IntoIterator<Item = DisplayColor>) -> Self {
+        let mut frame = Self::new(width, height, DisplayColor::White);
+        let mut count = 0;
+        for (i, color) in colors.into_iter().enumerate() {
+            frame.set_nybble(i, color);
+            count += 1;
+        }
+        assert_eq!(count, (width * height) as usize, "Wrong number of colours for the frame");
+        frame
+    }
+
+    /// Pixels not rendered from `palette` get the nearest ink.
+    pub fn from_rgb(rgb: &RgbImage, palette: &InkPalette) -> Self {
+        let lut = NearestInkLut::for_palette(palette);
+        let nybble = |pixel: &[u8]| {
+            let pixel = Rgb::from_slice(pixel);
+            let color = palette.display_color_of(pixel).unwrap_or_else(|| lut.nearest(*pixel));
+            u8::from(color)
+        };
+        let mut frame = Self::new(rgb.width(), rgb.height(), DisplayColor::White);
+        // two pixels to a byte
+        frame
+            .data
+            .par_iter_mut()
+            .zip(rgb.as_raw().par_chunks_exact(6))
+            .for_each(|(byte, pair)| *byte = nybble(&pair[..3]) << 4 | nybble(&pair[3..]));
+        frame
+    }
+
+    /// Renders the frame with `palette`'s colour for each ink, and [`INVALID_NYBBLE_RGB`] for
+    /// anything else.
+    pub fn to_rgb(&self, palette: &InkPalette) -> RgbImage {
+        let mut rgbs = [INVALID_NYBBLE_RGB; 16];
+        for (display_color, ink) in palette.inks() {
+            rgbs[u8::from(display_color) as usize] = ink.rgb;
+        }
+        let mut rgb = RgbImage::new(self.width, self.height);
+        for (pixel, nybble) in rgb.pixels_mut().zip(self.nybbles()) {
+            *pixel = rgbs[nybble as usize];
+        }
+        rgb
+    }
+
+    /// Every nybble that isn't one of `panel`'s inks, by value.
+    pub fn invalid_nybbles(&self, panel: &PanelProfile) -> Vec<InvalidNybbles> {
+        let mut valid = [false; 16];
+        for display_color in panel.colors() {
+            valid[u8::from(display_color) as usize] = true;
+        }
+        let mut invalid: Vec<InvalidNybbles> = Vec::new();
+        for (i, nybble) in self.nybbles().enumerate() {
+            if valid[nybble as usize] {
+                continue;
+            }
+            match invalid.iter_mut().find(|found| found.nybble == nybble) {
+                Some(found) => found.count += 1,
+                None => invalid.push(InvalidNybbles {
+                    nybble,
+                    count: 1,
+                    first: (i as u32 % self.width, i as u32 / self.width),
+                }),
+            }
+        }
+        invalid.sort_by_key(|found| found.nybble);
+        invalid
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// `None` if the nybble at `x`, `y` isn't a [`DisplayColor`] at all.
+    pub fn get_pixel(&self, x: u32, y: u32) -> Option<DisplayColor> {
+        DisplayColor::try_from(self.nybble(x, y)).ok()
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, color: DisplayColor) {
+        assert!(x < self.width && y < self.height, "Pixel {}x{} is outside the frame", x, y);
+        self.set_nybble((y * self.width + x) as usize, color);
+    }
+
+    /// The raw four-bit code at `x`, `y`, which isn't necessarily a valid [`DisplayColor`].
+    pub fn nybble(&self, x: u32, y: u32) -> u8 {
+        assert!(x < self.width && y < self.height, "Pixel {}x{} is outside the frame", x, y);
+        let i = (y * self.width + x) as usize;
+        let byte = self.data[i / 2];
+        if i.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F }
+    }
+
+    /// Every pixel, row by row, or the raw nybble where it isn't a [`DisplayColor`].
+    pub fn colors(&self) -> impl Iterator<Item = Result<DisplayColor, u8>> + '_ {
+        self.nybbles().map(DisplayColor::try_from)
+    }
+
+    /// Every pixel's raw code, row by row.
+    pub fn nybbles(&self) -> impl Iterator<Item = u8> + '_ {
+        self.data.iter().flat_map(|byte| [byte >> 4, byte & 0x0F])
+    }
+
+    /// One row's packed bytes.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let row_len = (self.width / 2) as usize;
+        &self.data[y as usize * row_len..(y as usize + 1) * row_len]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn set_nybble(&mut self, i: usize, color: DisplayColor) {
+        let byte = &mut self.data[i / 2];
+        *byte = if i.is_multiple_of(2) {
+            *byte & 0x0F | u8::from(color) << 4
+        } else {
+            *byte & 0xF0 | u8::from(color)
+        };
+    }
+}
+
+impl PackedRows for EpdFrame {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn for_each_row(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), FrameError> {
+        self.data.chunks((self.width / 2) as usize).for_each(f);
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! Drawing straight into a frame with `embedded-graphics`, for anything that isn't a photo.
+
+use crate::color::display_color::DisplayColor;
+use crate::frame::EpdFrame;
+use embedded_graphics::pixelcolor::raw::{RawData, RawU4};
+use embedded_graphics::prelude::{DrawTarget, OriginDimensions, PixelColor, Size};
+use embedded_graphics::Pixel;
+use std::convert::Infallible;
+
+impl PixelColor for DisplayColor {
+    type Raw = RawU4;
+}
+
+/// Fails with the nybble itself if it isn't a colour, rather than reading it as one.
+impl TryFrom<RawU4> for DisplayColor {
+    type Error = u8;
+
+    fn try_from(value: RawU4) -> Result<Self, Self::Error> {
+        DisplayColor::try_from(value.into_inner())
+    }
+}
+
+impl From<DisplayColor> for RawU4 {
+    fn from(value: DisplayColor) -> Self {
+        RawU4::new(value.into())
+    }
+}
+
+impl OriginDimensions for EpdFrame {
+    fn size(&self) -> Size {
+        Size::new(self.width(), self.height())
+    }
+}
+
+/// Pixels outside the frame are clipped.
+impl DrawTarget for EpdFrame {
+    type Color = DisplayColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y))
+                && x < self.width()
+                && y < self.height()
+            {
+                self.set_pixel(x, y, color);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        *self = EpdFrame::new(self.width(), self.height(), color);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::prelude::{Point, Primitive};
+    use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+    use embedded_graphics::Drawable;
+
+    #[test]
+    fn drawn_colours_read_back() {
+        let mut frame = EpdFrame::new(8, 4, DisplayColor::White);
+        Rectangle::new(Point::new(-2, 1), Size::new(5, 2))
+            .into_styled(PrimitiveStyle::with_fill(DisplayColor::Red))
+            .draw(&mut frame)
+            .unwrap();
+        assert_eq!(frame.get_pixel(2, 1), Some(DisplayColor::Red));
+        assert_eq!(frame.get_pixel(3, 1), Some(DisplayColor::White));
+        assert_eq!(frame.get_pixel(0, 0), Some(DisplayColor::White));
+        assert_eq!(DisplayColor::try_from(RawU4::from(DisplayColor::Blue)), Ok(DisplayColor::Blue));
+        assert_eq!(DisplayColor::try_from(RawU4::new(0xF)), Err(0xF));
+    }
+}
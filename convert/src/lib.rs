@@ -1,57 +1,456 @@
 extern crate core;
 
-mod color;
-mod display_constants;
-
-use crate::color::color_histogram_eq::equalize_color_histogram;
-use crate::color::{e_paper_color_map::EPaperColorMap, rgb_to_display_nybbles};
-use crate::display_constants::{PIXEL_HEIGHT, PIXEL_WIDTH};
-use image::error::{DecodingError, ImageFormatHint};
-use image::imageops::{dither, FilterType};
+pub mod adjust;
+pub mod calibration;
+pub mod color;
+pub mod container;
+pub mod dashboard;
+pub mod dither;
+pub mod fit;
+pub mod formats;
+pub mod frame;
+pub mod graphics;
+pub mod orientation;
+pub mod overlay;
+pub mod panel;
+pub mod quality;
+pub mod simulation;
+pub mod svg;
+mod text;
+
+use crate::adjust::Adjustments;
+use crate::color::{e_paper_color_map::EPaperColorMap, ink_palette::InkPalette, rgb_to_display_nybbles};
+use crate::container::{Compression, FrameFile, FrameView, SourceMetadata};
+use crate::dither::{dither, DiffusionSpace, Dither};
+use crate::fit::{fit, FitMode};
+use crate::frame::{EpdFrame, FrameError};
+use crate::orientation::{as_hung, orient, orient_turns, turn, Mounting, Orientation};
+use crate::overlay::{draw_overlays, Overlay, OverlayStage};
+use crate::panel::PanelProfile;
+use crate::quality::{measure, QualityReport};
+use crate::simulation::Simulation;
+use exif::{In, Tag, Value};
 use image::metadata::Orientation::NoTransforms;
-use image::{DynamicImage, EncodableLayout, ImageDecoder, ImageError, ImageReader};
-use std::fs::File;
-use std::io::Write;
+use image::{DynamicImage, GrayImage, ImageDecoder, ImageError, ImageReader, RgbImage};
+use std::fs;
+use std::io::{BufRead, Cursor, Read, Seek};
 use std::path::Path;
 use tracing::info;
 
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    pub panel: &'static PanelProfile,
+    pub dither: Dither,
+    pub diffusion_space: DiffusionSpace,
+    /// Measured inks; the panel's nominal ones when left out.
+    pub palette: Option<InkPalette>,
+    pub fit: FitMode,
+    /// Made to the picture once it's at the panel's size, before anything is drawn over it.
+    pub adjustments: Adjustments,
+    pub orientation: Orientation,
+    pub mounting: Mounting,
+    /// Text printed over the picture, in order.
+    pub overlays: Vec<Overlay>,
+    /// Give an SVG's flat fills the nearest ink instead of dithering them.
+    pub snap_flat_fills: bool,
+    /// How [`convert`] stores the frame file.
+    pub compression: Compression,
+    /// Render previews as the panel will look rather than in the palette's colours; [`convert`]
+    /// saves them turned the way the frame hangs.
+    pub simulation: Option<Simulation>,
+}
+
+/// Decodes an image of any supported format, applying its EXIF orientation.
+fn decode<R: BufRead + Seek>(reader: ImageReader<R>) -> Result<DynamicImage, ImageError> {
+    let mut decoder = reader.with_guessed_format()?.into_decoder()?;
+    let orientation = decoder.orientation().unwrap_or(NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Opens an image of any supported format, applying its EXIF orientation. SVGs come out at
+/// their own size.
+pub fn open_image(file: &Path) -> Result<DynamicImage, ImageError> {
+    let bytes = fs::read(file)?;
+    if svg::is_svg(&bytes) {
+        let tree = svg::parse(&bytes, file.parent(), false)?;
+        return Ok(DynamicImage::ImageRgb8(svg::rasterize(&tree)?));
+    }
+    decode_image(&bytes)
+}
+
+/// Decodes an in-memory image of any supported format, applying its EXIF orientation. SVGs come
+/// out at their own size.
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+    if svg::is_svg(bytes) {
+        return Ok(DynamicImage::ImageRgb8(svg::rasterize(&svg::parse(bytes, None, false)?)?));
+    }
+    if let Some(img) = formats::decode(bytes) {
+        return img;
+    }
+    decode(ImageReader::new(Cursor::new(bytes)))
+}
+
+/// What a frame records about `img`, decoded from `bytes`, including the caption and capture
+/// date from its EXIF if it has them.
+pub fn source_metadata(img: &DynamicImage, bytes: &[u8]) -> SourceMetadata {
+    let mut source = SourceMetadata {
+        width: img.width(),
+        height: img.height(),
+        ..SourceMetadata::default()
+    };
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) else {
+        return source;
+    };
+    let ascii = |tag| match exif.get_field(tag, In::PRIMARY).map(|field| &field.value) {
+        Some(Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+    source.caption = ascii(Tag::ImageDescription)
+        .map(|caption| String::from_utf8_lossy(&caption).trim().to_string())
+        .filter(|caption| !caption.is_empty());
+    source.captured = ascii(Tag::DateTimeOriginal)
+        .or_else(|| ascii(Tag::DateTime))
+        .and_then(|date_time| exif::DateTime::from_ascii(&date_time).ok())
+        .map(|date_time| format!("{:04}-{:02}-{:02}", date_time.year, date_time.month, date_time.day));
+    source
+}
+
+/// The result of a conversion.
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub frame: EpdFrame,
+    /// Name of the palette the frame was dithered with.
+    pub palette: String,
+    /// The image as it came in; the file name is up to the caller.
+    pub source: SourceMetadata,
+    /// The dithered image in the palette's colours, or as simulated, if [`Converter::preview`]
+    /// was asked for.
+    pub preview: Option<RgbImage>,
+    /// How close the frame comes to the picture, if [`Converter::quality_report`] was asked for.
+    pub quality: Option<QualityReport>,
+}
+
+/// Converts images to frames in memory, e.g.
+/// `Converter::new().fit(FitMode::Stretch).preview(true).convert_bytes(&upload)?`.
+#[derive(Debug, Clone, Default)]
+pub struct Converter {
+    options: ConvertOptions,
+    preview: bool,
+    quality_report: bool,
+}
+
+impl Conversion {
+    /// The frame with the palette and source recorded, ready to write out.
+    pub fn into_frame_file(self) -> FrameFile {
+        FrameFile {
+            frame: self.frame,
+            palette: Some(self.palette),
+            source: Some(self.source),
+        }
+    }
+}
+
+impl From<ConvertOptions> for Converter {
+    fn from(options: ConvertOptions) -> Self {
+        Self {
+            options,
+            preview: false,
+            quality_report: false,
+        }
+    }
+}
+
+impl Converter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn panel(mut self, panel: &'static PanelProfile) -> Self {
+        self.options.panel = panel;
+        self
+    }
+
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.options.dither = dither;
+        self
+    }
+
+    pub fn diffusion_space(mut self, diffusion_space: DiffusionSpace) -> Self {
+        self.options.diffusion_space = diffusion_space;
+        self
+    }
+
+    pub fn palette(mut self, palette: InkPalette) -> Self {
+        self.options.palette = Some(palette);
+        self
+    }
+
+    pub fn fit(mut self, fit: FitMode) -> Self {
+        self.options.fit = fit;
+        self
+    }
+
+    pub fn adjustments(mut self, adjustments: Adjustments) -> Self {
+        self.options.adjustments = adjustments;
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.options.orientation = orientation;
+        self
+    }
+
+    pub fn mounting(mut self, mounting: Mounting) -> Self {
+        self.options.mounting = mounting;
+        self
+    }
+
+    pub fn snap_flat_fills(mut self, snap_flat_fills: bool) -> Self {
+        self.options.snap_flat_fills = snap_flat_fills;
+        self
+    }
+
+    /// Makes previews look like the panel on the wall, with the measured inks if there's a
+    /// palette and the panel's typical ones if not.
+    pub fn simulation(mut self, simulation: Simulation) -> Self {
+        self.options.simulation = Some(simulation);
+        self
+    }
+
+    /// Adds text printed over the picture.
+    pub fn overlay(mut self, overlay: Overlay) -> Self {
+        self.options.overlays.push(overlay);
+        self
+    }
+
+    /// Also return the dithered image, e.g. to show what the panel will look like.
+    pub fn preview(mut self, preview: bool) -> Self {
+        self.preview = preview;
+        self
+    }
+
+    /// Also measure the result against the picture, e.g. to compare settings.
+    pub fn quality_report(mut self, quality_report: bool) -> Self {
+        self.quality_report = quality_report;
+        self
+    }
+
+    pub fn options(&self) -> &ConvertOptions {
+        &self.options
+    }
+
+    /// `img` should already be upright, e.g. from [`open_image`] or [`decode_image`]. Caption and
+    /// date overlays need the EXIF that [`Converter::convert_bytes`] or [`source_metadata`] reads.
+    pub fn convert_image(&self, img: DynamicImage) -> Conversion {
+        let source = SourceMetadata {
+            width: img.width(),
+            height: img.height(),
+            ..SourceMetadata::default()
+        };
+        self.convert_source(img, source)
+    }
+
+    /// Like [`Converter::convert_image`], with `source` e.g. from [`source_metadata`].
+    pub fn convert_source(&self, img: DynamicImage, source: SourceMetadata) -> Conversion {
+        let options = &self.options;
+        let panel = options.panel;
+        let palette = self.ink_palette();
+        info!("Rotating ({} on a {} {} frame)...", options.orientation, options.mounting, panel);
+        let turns = orient_turns(img.width(), img.height(), options.orientation, options.mounting, panel);
+        let img = orient(img, options.orientation, options.mounting, panel);
+        info!("Rotated. Resizing ({})...", options.fit);
+        let img = fit(&img, panel.width, panel.height, options.fit, &palette);
+        info!("Resized.");
+        self.finish(img, None, source, turns, palette)
+    }
+
+    /// Converts an SVG, rasterised at exactly the panel's resolution. Relative image links are
+    /// resolved against `resources_dir`.
+    pub fn convert_svg(&self, bytes: &[u8], resources_dir: Option<&Path>) -> Result<Conversion, ImageError> {
+        let options = &self.options;
+        let panel = options.panel;
+        let palette = self.ink_palette();
+        let tree = svg::parse(bytes, resources_dir, options.snap_flat_fills)?;
+        let size = tree.size().to_int_size();
+        let source = SourceMetadata {
+            width: size.width(),
+            height: size.height(),
+            ..SourceMetadata::default()
+        };
+        let turns = orient_turns(size.width(), size.height(), options.orientation, options.mounting, panel);
+        let (width, height) = match turns % 2 {
+            0 => (panel.width, panel.height),
+            _ => (panel.height, panel.width),
+        };
+        info!("Rasterizing SVG at {}x{} ({})...", width, height, options.fit);
+        let mut img = svg::rasterize_fitted(&tree, width, height, options.fit, &palette)?;
+        let snapped = options.snap_flat_fills.then(|| {
+            let mask = svg::snap_flat_fills(&mut img, &tree, &EPaperColorMap::with_palette(palette.clone()));
+            turn(&mask, turns)
+        });
+        info!("Rasterized.");
+        Ok(self.finish(turn(&img, turns), snapped, source, turns, palette))
+    }
+
+    /// Measured inks for the panel, or its nominal ones.
+    fn ink_palette(&self) -> InkPalette {
+        match &self.options.palette {
+            Some(palette) => palette.for_panel(self.options.panel),
+            None => self.options.panel.palette(),
+        }
+    }
+
+    /// The inks as they look: measured, or the panel's typical ones.
+    fn seen_palette(&self) -> InkPalette {
+        match &self.options.palette {
+            Some(palette) => palette.for_panel(self.options.panel),
+            None => self.options.panel.typical_palette(),
+        }
+    }
+
+    /// Adjusts, overlays, dithers and packs an image already in the panel's native layout. Pixels in
+    /// `fixed` are already inks and come through dithering untouched.
+    fn finish(
+        &self,
+        mut img: RgbImage,
+        fixed: Option<GrayImage>,
+        source: SourceMetadata,
+        turns: u8,
+        palette: InkPalette,
+    ) -> Conversion {
+        let options = &self.options;
+        if !options.adjustments.is_neutral() {
+            info!("Adjusting {:?}...", options.adjustments);
+            options.adjustments.apply(&mut img, fixed.as_ref());
+        }
+        draw_overlays(&mut img, &options.overlays, OverlayStage::BeforeDither, &source, turns, &palette);
+        info!("Dithering ({} in {})...", options.dither, options.diffusion_space);
+        let epd_map = EPaperColorMap::with_palette(palette);
+        let undithered = (fixed.is_some() || self.quality_report).then(|| img.clone());
+        dither(&mut img, &epd_map, options.dither, options.diffusion_space);
+        if let Some((fixed, undithered)) = fixed.zip(undithered.as_ref()) {
+            for ((pixel, original), mask) in img.pixels_mut().zip(undithered.pixels()).zip(fixed.pixels()) {
+                if mask.0[0] != 0 {
+                    *pixel = *original;
+                }
+            }
+        }
+        // text drawn after dithering isn't in the picture, so it's left out of the comparison
+        let dithered = self.quality_report.then(|| img.clone());
+        draw_overlays(&mut img, &options.overlays, OverlayStage::AfterDither, &source, turns, epd_map.palette());
+        info!("Dithered. Packing bytes...");
+        let frame = rgb_to_display_nybbles(&img, epd_map.palette());
+        info!("Image packed to nybble format");
+        let quality = undithered
+            .zip(dithered)
+            .map(|(undithered, dithered)| measure(&undithered, &dithered, &frame, epd_map.palette()));
+        let preview = match options.simulation {
+            Some(simulation) if self.preview => Some(simulation.render(&frame, &self.seen_palette())),
+            _ => self.preview.then_some(img),
+        };
+        Conversion {
+            frame,
+            palette: epd_map.palette().name().to_string(),
+            source,
+            preview,
+            quality,
+        }
+    }
+
+    /// SVGs go through [`Converter::convert_svg`].
+    pub fn convert_bytes(&self, bytes: &[u8]) -> Result<Conversion, ImageError> {
+        if svg::is_svg(bytes) {
+            return self.convert_svg(bytes, None);
+        }
+        let img = decode_image(bytes)?;
+        let source = source_metadata(&img, bytes);
+        Ok(self.convert_source(img, source))
+    }
+
+    /// Reads `reader` to the end first; decoders need to seek.
+    pub fn convert_reader<R: Read>(&self, mut reader: R) -> Result<Conversion, ImageError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        self.convert_bytes(&bytes)
+    }
+}
+
+/// Reads and verifies a frame file as written by [`convert`], or a legacy raw one for `panel`.
+pub fn read_frame(file: &Path, panel: &PanelProfile) -> Result<FrameFile, FrameError> {
+    FrameFile::from_bytes(&fs::read(file)?, panel.width, panel.height)
+}
+
+/// Verifies the contents of a frame file, or a legacy raw one for `panel`, without unpacking it.
+pub fn view_frame<'a>(bytes: &'a [u8], panel: &PanelProfile) -> Result<FrameView<'a>, FrameError> {
+    FrameView::new(bytes, panel.width, panel.height)
+}
+
+/// Identifies a conversion by its source file's contents and the settings, so a frame needn't be
+/// made again from the same file with the same settings.
+pub fn fingerprint(bytes: &[u8], options: &ConvertOptions) -> String {
+    // a palette's inks are kept in a HashMap, whose order changes from run to run
+    let palette = options.palette.as_ref().map(|palette| (palette.name(), palette.inks().collect::<Vec<_>>()));
+    let options = ConvertOptions {
+        palette: None,
+        ..options.clone()
+    };
+    let settings = format!("{} {:?} {:?}", env!("CARGO_PKG_VERSION"), options, palette);
+    format!("{:08x}{:08x}", crc32fast::hash(bytes), crc32fast::hash(settings.as_bytes()))
+}
+
 pub fn convert(
     file: &Path,
     out_file: &Path,
     dithered_file: Option<&Path>,
+    options: &ConvertOptions,
 ) -> Result<(), ImageError> {
-    let mut decoder = ImageReader::open(&file)?
-        .with_guessed_format()?
-        .into_decoder()?;
-    let orientation = decoder.orientation().unwrap_or(NoTransforms);
-    let mut img = DynamicImage::from_decoder(decoder)?;
-    img.apply_orientation(orientation);
-    let img = img;
-    info!("Opened image {}. Rotating...", &file.display());
-    let img = img.rotate90();
-    info!("Rotated. Resizing...");
-    let img = img.resize_to_fill(PIXEL_WIDTH, PIXEL_HEIGHT, FilterType::Lanczos3);
-    info!("Resized. Equalizing Histogram...");
-    // let mut img = equalize_color_histogram(&img).ok_or(ImageError::Decoding(
-    //     DecodingError::from_format_hint(ImageFormatHint::Name("Grayscale conversion failed".to_string())),
-    // ))?;
-    let mut img = img.into_rgb8();
-    info!("Histogram Equalized. Dithering...");
-
-    let epd_map = EPaperColorMap::new();
-    dither(&mut img, &epd_map);
-    info!("Dithered");
-
-    if let Some(dither_path) = dithered_file {
-        img.save(dither_path)?;
+    convert_file(file, out_file, dithered_file, options, false)?;
+    Ok(())
+}
+
+/// Like [`convert`], also measuring how close the frame comes to the picture.
+pub fn convert_with_report(
+    file: &Path,
+    out_file: &Path,
+    dithered_file: Option<&Path>,
+    options: &ConvertOptions,
+) -> Result<QualityReport, ImageError> {
+    let quality = convert_file(file, out_file, dithered_file, options, true)?;
+    Ok(quality.expect("Quality report was asked for"))
+}
+
+fn convert_file(
+    file: &Path,
+    out_file: &Path,
+    dithered_file: Option<&Path>,
+    options: &ConvertOptions,
+    quality_report: bool,
+) -> Result<Option<QualityReport>, ImageError> {
+    let bytes = fs::read(file)?;
+    info!("Read image {}", &file.display());
+    let converter = Converter::from(options.clone())
+        .preview(dithered_file.is_some())
+        .quality_report(quality_report);
+    let mut conversion = match svg::is_svg(&bytes) {
+        true => converter.convert_svg(&bytes, file.parent())?,
+        false => converter.convert_bytes(&bytes)?,
+    };
+    conversion.source.file_name = file.file_name().map(|name| name.to_string_lossy().to_string());
+    conversion.source.fingerprint = Some(fingerprint(&bytes, options));
+    let quality = conversion.quality.take();
+
+    if let Some((dither_path, preview)) = dithered_file.zip(conversion.preview.take()) {
+        // a simulated preview is of the frame on the wall, so it's turned the way the frame hangs
+        let preview = match options.simulation {
+            Some(_) => as_hung(&preview, options.mounting, options.panel),
+            None => preview,
+        };
+        preview.save(dither_path)?;
         info!("Saved dithered image");
     }
-    info!("Packing bytes...");
-    let epd_image = rgb_to_display_nybbles(&img);
-    info!("Image packed to nybble format. Saving...");
-
-    let mut file = File::create(&out_file)?;
-    file.write_all(epd_image.as_bytes())?;
+    fs::write(out_file, conversion.into_frame_file().to_bytes(options.compression))?;
     info!("Image written. Done");
-    Ok(())
+    Ok(quality)
 }
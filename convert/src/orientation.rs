@@ -0,0 +1,163 @@
+use crate::panel::PanelProfile;
+use image::imageops::{rotate180, rotate270, rotate90};
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// How the panel hangs on the wall. `Landscape` is `Portrait` turned a quarter counter-clockwise,
+/// and whichever of the two matches the panel's shape is its native layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mounting {
+    Portrait,
+    #[default]
+    Landscape,
+    PortraitFlipped,
+    LandscapeFlipped,
+}
+
+/// Which way up the picture is laid out on the frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Orientation {
+    /// Whichever of landscape or portrait crops or mattes the fewest pixels of the image.
+    #[default]
+    Auto,
+    Landscape,
+    Portrait,
+}
+
+impl Mounting {
+    pub const ALL: [Mounting; 4] = [
+        Mounting::Portrait,
+        Mounting::Landscape,
+        Mounting::PortraitFlipped,
+        Mounting::LandscapeFlipped,
+    ];
+
+    /// Clockwise quarter turns taking an upright picture to `panel`'s native layout.
+    fn quarter_turns(self, panel: &PanelProfile) -> u8 {
+        let from_portrait = match self {
+            Mounting::Portrait => 0,
+            Mounting::Landscape => 1,
+            Mounting::PortraitFlipped => 2,
+            Mounting::LandscapeFlipped => 3,
+        };
+        (from_portrait + if panel.is_landscape() { 3 } else { 0 }) % 4
+    }
+
+    pub(crate) fn is_landscape(self) -> bool {
+        matches!(self, Mounting::Landscape | Mounting::LandscapeFlipped)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Mounting::Portrait => "portrait",
+            Mounting::Landscape => "landscape",
+            Mounting::PortraitFlipped => "portrait-flipped",
+            Mounting::LandscapeFlipped => "landscape-flipped",
+        }
+    }
+}
+
+impl Orientation {
+    pub const ALL: [Orientation; 3] = [Orientation::Auto, Orientation::Landscape, Orientation::Portrait];
+
+    fn name(self) -> &'static str {
+        match self {
+            Orientation::Auto => "auto",
+            Orientation::Landscape => "landscape",
+            Orientation::Portrait => "portrait",
+        }
+    }
+}
+
+impl Display for Mounting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Mounting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Mounting::ALL
+            .into_iter()
+            .find(|mounting| mounting.name() == s)
+            .ok_or_else(|| format!("Unknown mounting: {}", s))
+    }
+}
+
+impl Display for Orientation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Orientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Orientation::ALL
+            .into_iter()
+            .find(|orientation| orientation.name() == s)
+            .ok_or_else(|| format!("Unknown orientation: {}", s))
+    }
+}
+
+/// Whether a `width`×`height` image should be laid out landscape. The two layouts are the same
+/// frame transposed, so the one matching the image's own aspect ratio always crops (or mattes)
+/// the least; square images stay with the way the frame hangs.
+fn wants_landscape(width: u32, height: u32, orientation: Orientation, mounting: Mounting) -> bool {
+    match orientation {
+        Orientation::Landscape => true,
+        Orientation::Portrait => false,
+        Orientation::Auto if width == height => mounting.is_landscape(),
+        Orientation::Auto => width > height,
+    }
+}
+
+/// Clockwise quarter turns [`orient`] gives a `width`×`height` image.
+pub(crate) fn orient_turns(
+    width: u32,
+    height: u32,
+    orientation: Orientation,
+    mounting: Mounting,
+    panel: &PanelProfile,
+) -> u8 {
+    let across = wants_landscape(width, height, orientation, mounting) != mounting.is_landscape();
+    (mounting.quarter_turns(panel) + if across { 3 } else { 0 }) % 4
+}
+
+/// `img` turned a quarter clockwise `turns` times.
+pub(crate) fn turn<I>(img: &I, turns: u8) -> ImageBuffer<I::Pixel, Vec<<I::Pixel as Pixel>::Subpixel>>
+where
+    I: GenericImageView,
+    I::Pixel: 'static,
+{
+    match turns % 4 {
+        0 => ImageBuffer::from_fn(img.width(), img.height(), |x, y| img.get_pixel(x, y)),
+        1 => rotate90(img),
+        2 => rotate180(img),
+        _ => rotate270(img),
+    }
+}
+
+/// Turns `img`, in `panel`'s native layout, the way the frame hangs, e.g. to show a frame as it'll
+/// be seen.
+pub fn as_hung(img: &RgbImage, mounting: Mounting, panel: &PanelProfile) -> RgbImage {
+    turn(img, 4 - mounting.quarter_turns(panel))
+}
+
+/// Turns an upright `img` into `panel`'s native layout. A picture laid out across the way the
+/// frame hangs comes out upright once the frame is turned a quarter clockwise.
+pub fn orient(img: DynamicImage, orientation: Orientation, mounting: Mounting, panel: &PanelProfile) -> DynamicImage {
+    match orient_turns(img.width(), img.height(), orientation, mounting, panel) {
+        0 => img,
+        1 => img.rotate90(),
+        2 => img.rotate180(),
+        _ => img.rotate270(),
+    }
+}
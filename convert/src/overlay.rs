@@ -0,0 +1,230 @@
+//! Text printed over the picture, e.g. who's in it and when it was taken.
+
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use crate::container::SourceMetadata;
+use crate::orientation::turn;
+use crate::text::Text;
+use image::RgbImage;
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tracing::warn;
+
+/// Smallest line height, in pixels, that's still legible on the panel.
+pub const MIN_OVERLAY_SIZE: f32 = 8.0;
+
+/// What an overlay says.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverlayText {
+    /// The image's own description, from its EXIF.
+    Caption,
+    /// When the photo was taken, from its EXIF.
+    CaptureDate,
+    Custom(String),
+}
+
+/// Which corner or edge of the picture, as it hangs, the text sits against.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverlayPosition {
+    TopLeft,
+    Top,
+    TopRight,
+    BottomLeft,
+    Bottom,
+    #[default]
+    BottomRight,
+}
+
+/// When the text is drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum OverlayStage {
+    /// Drawn in ink colours onto the dithered picture, so every edge stays sharp.
+    #[default]
+    AfterDither,
+    /// Dithered along with the picture; edges pick up some of the diffused error.
+    BeforeDither,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlay {
+    pub text: OverlayText,
+    pub position: OverlayPosition,
+    /// Line height in pixels.
+    pub size: f32,
+    pub color: DisplayColor,
+    /// Box drawn behind the text, if any.
+    pub backing: Option<DisplayColor>,
+    pub stage: OverlayStage,
+}
+
+impl Overlay {
+    pub fn new(text: OverlayText) -> Self {
+        Self {
+            text,
+            position: OverlayPosition::default(),
+            size: 48.0,
+            color: DisplayColor::White,
+            backing: Some(DisplayColor::Black),
+            stage: OverlayStage::default(),
+        }
+    }
+
+    /// Nothing when the source doesn't have the caption or date asked for.
+    fn resolve<'a>(&'a self, source: &'a SourceMetadata) -> Option<&'a str> {
+        match &self.text {
+            OverlayText::Caption => source.caption.as_deref(),
+            OverlayText::CaptureDate => source.captured.as_deref(),
+            OverlayText::Custom(text) => Some(text),
+        }
+    }
+}
+
+impl OverlayPosition {
+    pub const ALL: [OverlayPosition; 6] = [
+        OverlayPosition::TopLeft,
+        OverlayPosition::Top,
+        OverlayPosition::TopRight,
+        OverlayPosition::BottomLeft,
+        OverlayPosition::Bottom,
+        OverlayPosition::BottomRight,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            OverlayPosition::TopLeft => "top-left",
+            OverlayPosition::Top => "top",
+            OverlayPosition::TopRight => "top-right",
+            OverlayPosition::BottomLeft => "bottom-left",
+            OverlayPosition::Bottom => "bottom",
+            OverlayPosition::BottomRight => "bottom-right",
+        }
+    }
+
+    /// Where a `width`×`height` block goes in a `frame_width`×`frame_height` picture, `inset`
+    /// further from the edge than `margin`.
+    fn place(self, width: u32, height: u32, frame_width: u32, frame_height: u32, margin: u32, inset: u32) -> (i32, i32) {
+        let left = margin as i32;
+        let center = (frame_width as i32 - width as i32) / 2;
+        let right = frame_width as i32 - width as i32 - margin as i32;
+        let top = (margin + inset) as i32;
+        let bottom = frame_height as i32 - height as i32 - (margin + inset) as i32;
+        match self {
+            OverlayPosition::TopLeft => (left, top),
+            OverlayPosition::Top => (center, top),
+            OverlayPosition::TopRight => (right, top),
+            OverlayPosition::BottomLeft => (left, bottom),
+            OverlayPosition::Bottom => (center, bottom),
+            OverlayPosition::BottomRight => (right, bottom),
+        }
+    }
+}
+
+impl Display for OverlayText {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlayText::Caption => f.write_str("caption"),
+            OverlayText::CaptureDate => f.write_str("date"),
+            OverlayText::Custom(text) => write!(f, "text:{}", text),
+        }
+    }
+}
+
+/// `caption`, `date`, or `text:` followed by the text itself.
+impl FromStr for OverlayText {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "caption" => Ok(OverlayText::Caption),
+            "date" => Ok(OverlayText::CaptureDate),
+            _ => s
+                .strip_prefix("text:")
+                .map(|text| OverlayText::Custom(text.to_string()))
+                .ok_or_else(|| format!("Unknown overlay text: {}", s)),
+        }
+    }
+}
+
+impl Display for OverlayPosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for OverlayPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OverlayPosition::ALL
+            .into_iter()
+            .find(|position| position.name() == s)
+            .ok_or_else(|| format!("Unknown overlay position: {}", s))
+    }
+}
+
+/// Draws the `stage` overlays onto `img`, which is in the panel's native layout after `turns`
+/// clockwise quarter turns, so the text comes out upright on the wall. Overlays sharing a
+/// position stack away from the edge in order.
+pub(crate) fn draw_overlays(
+    img: &mut RgbImage,
+    overlays: &[Overlay],
+    stage: OverlayStage,
+    source: &SourceMetadata,
+    turns: u8,
+    palette: &InkPalette,
+) {
+    let overlays: Vec<&Overlay> = overlays.iter().filter(|overlay| overlay.stage == stage).collect();
+    if overlays.is_empty() {
+        return;
+    }
+    let mut upright = turn(img, 4 - turns);
+    let mut insets = [0; OverlayPosition::ALL.len()];
+    for overlay in overlays {
+        let inset = &mut insets[overlay.position as usize];
+        match overlay.resolve(source) {
+            Some(text) => *inset += draw_text(&mut upright, text, overlay, *inset, palette),
+            None => warn!("The image has no {}, leaving it out", overlay.text),
+        }
+    }
+    *img = turn(&upright, turns);
+}
+
+/// Returns the height taken up, including the gap to whatever is stacked next.
+fn draw_text(img: &mut RgbImage, text: &str, overlay: &Overlay, inset: u32, palette: &InkPalette) -> u32 {
+    let font = Text::new(overlay.size);
+    let lines: Vec<&str> = text.lines().collect();
+    let line_height = font.line_height();
+    let width = lines.iter().map(|line| font.width(line)).max().unwrap_or(0);
+    let height = line_height * lines.len() as u32;
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    let padding = (overlay.size / 4.0).round() as u32;
+    let margin = (overlay.size / 2.0).round() as u32;
+    let (left, top) = overlay.position.place(
+        width + 2 * padding,
+        height + 2 * padding,
+        img.width(),
+        img.height(),
+        margin,
+        inset,
+    );
+
+    if let Some(backing) = overlay.backing {
+        let backing_rect = Rect::at(left, top).of_size(width + 2 * padding, height + 2 * padding);
+        draw_filled_rect_mut(img, backing_rect, palette.rgb(backing));
+    }
+    for (i, line) in lines.iter().enumerate() {
+        // lines line up with each other the same way the block lines up with the frame
+        let indent = match overlay.position {
+            OverlayPosition::TopLeft | OverlayPosition::BottomLeft => 0,
+            OverlayPosition::Top | OverlayPosition::Bottom => (width - font.width(line)) / 2,
+            OverlayPosition::TopRight | OverlayPosition::BottomRight => width - font.width(line),
+        };
+        let line_top = top + (padding + i as u32 * line_height) as i32;
+        font.draw(img, line, left + (padding + indent) as i32, line_top, palette.rgb(overlay.color));
+    }
+    height + 2 * padding + padding
+}
@@ -0,0 +1,208 @@
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::{Ink, InkPalette};
+use crate::container::Packing;
+use image::Rgb;
+use std::fmt::{Display, Formatter};
+
+/// Which controller chip(s) a command or pixel data goes to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Controller {
+    Main,
+    Peri,
+    Both,
+}
+
+/// How the pixel data is divided between controller chips.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ControllerSplit {
+    /// One controller takes whole rows.
+    Single,
+    /// The main controller takes the left half of every row, the peri controller the right half.
+    HalfRows,
+}
+
+/// One ink the panel can show.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PanelInk {
+    pub color: DisplayColor,
+    /// The nybble the controller takes for this ink.
+    pub code: u8,
+    /// What the ink looks like until the panel is calibrated.
+    pub rgb: [u8; 3],
+    /// Roughly how the ink looks under indoor light, muted as real panels are; previews of an
+    /// uncalibrated panel use it.
+    pub typical: [u8; 3],
+}
+
+/// One step of the controller's boot sequence.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InitCommand {
+    pub controller: Controller,
+    pub command: u8,
+    pub data: &'static [u8],
+}
+
+/// Everything that differs between the e-paper panels this crate can drive.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PanelProfile {
+    pub name: &'static str,
+    /// Native width in pixels, as the controller scans it.
+    pub width: u32,
+    pub height: u32,
+    pub split: ControllerSplit,
+    pub packing: Packing,
+    pub inks: &'static [PanelInk],
+    pub init: &'static [InitCommand],
+}
+
+const fn init(controller: Controller, command: u8, data: &'static [u8]) -> InitCommand {
+    InitCommand { controller, command, data }
+}
+
+const fn ink(color: DisplayColor, code: u8, rgb: [u8; 3], typical: [u8; 3]) -> PanelInk {
+    PanelInk {
+        color,
+        code,
+        rgb,
+        typical,
+    }
+}
+
+/// Waveshare 13.3" Spectra 6 (E6), two controllers.
+pub static SPECTRA_6_13IN3: PanelProfile = PanelProfile {
+    name: "13in3-e6",
+    width: 1_200,
+    height: 1_600,
+    split: ControllerSplit::HalfRows,
+    packing: Packing::Nybbles,
+    inks: &[
+        ink(DisplayColor::Black, 0x0, [0, 0, 0], [28, 28, 32]),
+        ink(DisplayColor::White, 0x1, [255, 255, 255], [188, 188, 180]),
+        ink(DisplayColor::Yellow, 0x2, [255, 243, 57], [205, 190, 20]),
+        ink(DisplayColor::Red, 0x3, [191, 2, 1], [150, 30, 25]),
+        ink(DisplayColor::Blue, 0x5, [100, 64, 255], [30, 70, 150]),
+        ink(DisplayColor::Green, 0x6, [68, 138, 28], [40, 95, 65]),
+    ],
+    init: &[
+        init(Controller::Main, 0x74, &[0xC0, 0x1C, 0x1C, 0xCC, 0xCC, 0xCC, 0x15, 0x15, 0x55]),
+        init(Controller::Both, 0xF0, &[0x49, 0x55, 0x13, 0x5D, 0x05, 0x10]),
+        init(Controller::Both, 0x00, &[0xDF, 0x69]),
+        init(Controller::Both, 0x50, &[0xF7]),
+        init(Controller::Both, 0x60, &[0x03, 0x03]),
+        init(Controller::Both, 0x86, &[0x10]),
+        init(Controller::Both, 0xE3, &[0x22]),
+        init(Controller::Both, 0xE0, &[0x01]),
+        init(Controller::Both, 0x61, &[0x04, 0xB0, 0x03, 0x20]),
+        init(Controller::Main, 0x01, &[0x0F, 0x00, 0x28, 0x2C, 0x28, 0x38]),
+        init(Controller::Main, 0xB6, &[0x07]),
+        init(Controller::Main, 0x06, &[0xE8, 0x28]),
+        init(Controller::Main, 0xB7, &[0x01]),
+        init(Controller::Main, 0x05, &[0xE8, 0x28]),
+        init(Controller::Main, 0xB0, &[0x01]),
+        init(Controller::Main, 0xB1, &[0x02]),
+    ],
+};
+
+/// Waveshare 7.3" 7-colour ACeP (F), one controller, natively landscape.
+pub static ACEP_7IN3: PanelProfile = PanelProfile {
+    name: "7in3-acep",
+    width: 800,
+    height: 480,
+    split: ControllerSplit::Single,
+    packing: Packing::Nybbles,
+    inks: &[
+        ink(DisplayColor::Black, 0x0, [0, 0, 0], [38, 36, 44]),
+        ink(DisplayColor::White, 0x1, [255, 255, 255], [180, 180, 172]),
+        ink(DisplayColor::Green, 0x2, [0, 255, 0], [50, 95, 65]),
+        ink(DisplayColor::Blue, 0x3, [0, 0, 255], [60, 65, 120]),
+        ink(DisplayColor::Red, 0x4, [255, 0, 0], [150, 55, 50]),
+        ink(DisplayColor::Yellow, 0x5, [255, 255, 0], [200, 185, 60]),
+        ink(DisplayColor::Orange, 0x6, [255, 128, 0], [180, 100, 60]),
+    ],
+    init: &[
+        init(Controller::Main, 0xAA, &[0x49, 0x55, 0x20, 0x08, 0x09, 0x18]),
+        init(Controller::Main, 0x01, &[0x3F, 0x00, 0x32, 0x2A, 0x0E, 0x2A]),
+        init(Controller::Main, 0x00, &[0x5F, 0x69]),
+        init(Controller::Main, 0x03, &[0x00, 0x54, 0x00, 0x44]),
+        init(Controller::Main, 0x05, &[0x40, 0x1F, 0x1F, 0x2C]),
+        init(Controller::Main, 0x06, &[0x6F, 0x1F, 0x1F, 0x22]),
+        init(Controller::Main, 0x08, &[0x6F, 0x1F, 0x1F, 0x22]),
+        init(Controller::Main, 0x13, &[0x00, 0x04]),
+        init(Controller::Main, 0x30, &[0x3C]),
+        init(Controller::Main, 0x41, &[0x00]),
+        init(Controller::Main, 0x50, &[0x3F]),
+        init(Controller::Main, 0x60, &[0x02, 0x00]),
+        init(Controller::Main, 0x61, &[0x03, 0x20, 0x01, 0xE0]),
+        init(Controller::Main, 0x82, &[0x1E]),
+        init(Controller::Main, 0x84, &[0x00]),
+        init(Controller::Main, 0x86, &[0x00]),
+        init(Controller::Main, 0xE3, &[0x2F]),
+        init(Controller::Main, 0xE0, &[0x00]),
+        init(Controller::Main, 0xE6, &[0x00]),
+    ],
+};
+
+impl PanelProfile {
+    pub const ALL: [&'static PanelProfile; 2] = [&SPECTRA_6_13IN3, &ACEP_7IN3];
+
+    pub fn by_name(name: &str) -> Result<&'static PanelProfile, String> {
+        PanelProfile::ALL
+            .into_iter()
+            .find(|panel| panel.name == name)
+            .ok_or_else(|| format!("Unknown panel: {}", name))
+    }
+
+    pub fn is_landscape(&self) -> bool {
+        self.width > self.height
+    }
+
+    pub fn colors(&self) -> impl Iterator<Item = DisplayColor> + '_ {
+        self.inks.iter().map(|ink| ink.color)
+    }
+
+    /// `None` if the panel doesn't have `color`.
+    pub fn ink(&self, color: DisplayColor) -> Option<&PanelInk> {
+        self.inks.iter().find(|ink| ink.color == color)
+    }
+
+    /// The uncalibrated palette.
+    pub fn palette(&self) -> InkPalette {
+        InkPalette::new(
+            InkPalette::DEFAULT_NAME,
+            self.inks.iter().map(|ink| (ink.color, Ink::from_rgb(Rgb::from(ink.rgb)))),
+        )
+    }
+
+    /// How the inks typically look, for previews when the panel hasn't been calibrated.
+    pub fn typical_palette(&self) -> InkPalette {
+        InkPalette::new(
+            "typical",
+            self.inks.iter().map(|ink| (ink.color, Ink::from_rgb(Rgb::from(ink.typical)))),
+        )
+    }
+
+    /// Maps every packed byte of a frame to the byte this panel's controller takes. Nybbles that
+    /// aren't one of its inks go as white rather than as codes the controller may misread.
+    pub fn wire_bytes(&self) -> [u8; 256] {
+        let white = self.ink(DisplayColor::White).expect("Every panel has white").code;
+        let code = |nybble: u8| {
+            DisplayColor::try_from(nybble)
+                .ok()
+                .and_then(|color| self.ink(color))
+                .map_or(white, |ink| ink.code)
+        };
+        std::array::from_fn(|byte| code(byte as u8 >> 4) << 4 | code(byte as u8 & 0x0F))
+    }
+}
+
+impl Default for &'static PanelProfile {
+    fn default() -> Self {
+        &SPECTRA_6_13IN3
+    }
+}
+
+impl Display for PanelProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name)
+    }
+}
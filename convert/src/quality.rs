@@ -0,0 +1,195 @@
+//! How closely a dithered frame reproduces the picture it was made from, seen from far enough
+//! away that the dither blends.
+
+use crate::color::display_color::DisplayColor;
+use crate::color::ink_palette::InkPalette;
+use crate::frame::EpdFrame;
+use image::imageops::fast_blur;
+use image::{Rgb, Rgb32FImage, RgbImage};
+use palette::{IntoColor, LinSrgb, Oklab, Srgb};
+use rayon::prelude::*;
+
+/// Roughly how far dither dots spread into each other at arm's length.
+const VIEWING_BLUR_SIGMA: f32 = 2.0;
+/// Leeway outside the inks' hull, in linear light, so the inks themselves and 8-bit rounding
+/// don't count as out of gamut.
+const GAMUT_TOLERANCE: f32 = 1e-3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityReport {
+    /// Average Oklab distance between the blurred picture and the blurred frame.
+    pub mean_delta_e: f32,
+    /// Oklab distance 95% of pixels are within.
+    pub p95_delta_e: f32,
+    /// Pixels given each of the palette's inks.
+    pub ink_counts: Vec<(DisplayColor, u64)>,
+    /// Share of the picture's pixels, from 0 to 1, that no mix of the inks can reproduce.
+    pub out_of_gamut: f32,
+}
+
+impl QualityReport {
+    pub fn pixels(&self) -> u64 {
+        self.ink_counts.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// Compares `source`, the picture as it went into dithering, with `dithered`, what came out
+/// rendered in `palette`'s colours. Inks are counted in `frame`, so they include anything drawn
+/// after dithering.
+pub fn measure(source: &RgbImage, dithered: &RgbImage, frame: &EpdFrame, palette: &InkPalette) -> QualityReport {
+    let seen_source = fast_blur(&to_linear(source), VIEWING_BLUR_SIGMA);
+    let seen_dithered = fast_blur(&to_linear(dithered), VIEWING_BLUR_SIGMA);
+    let mut delta_es: Vec<f32> = seen_source
+        .par_pixels()
+        .zip(seen_dithered.par_pixels())
+        .map(|(wanted, got)| {
+            let (wanted, got) = (linear_to_oklab(wanted), linear_to_oklab(got));
+            [wanted.l - got.l, wanted.a - got.a, wanted.b - got.b]
+                .iter()
+                .map(|d| d * d)
+                .sum::<f32>()
+                .sqrt()
+        })
+        .collect();
+    let mean_delta_e = delta_es.iter().sum::<f32>() / delta_es.len().max(1) as f32;
+    let p95_delta_e = match delta_es.len() {
+        0 => 0.0,
+        len => *delta_es.select_nth_unstable_by((len - 1) * 95 / 100, f32::total_cmp).1,
+    };
+
+    let mut counts = [0u64; 16];
+    for nybble in frame.nybbles() {
+        counts[nybble as usize] += 1;
+    }
+    let ink_counts = palette
+        .inks()
+        .map(|(display_color, _)| (display_color, counts[u8::from(display_color) as usize]))
+        .collect();
+
+    let hull = hull_planes(&palette.inks().map(|(_, ink)| srgb_to_linear(&ink.rgb)).collect::<Vec<_>>());
+    let outside = source
+        .par_pixels()
+        .filter(|pixel| {
+            let linear = srgb_to_linear(pixel);
+            hull.iter().any(|(normal, offset)| dot(*normal, linear) > offset + GAMUT_TOLERANCE)
+        })
+        .count();
+
+    QualityReport {
+        mean_delta_e,
+        p95_delta_e,
+        ink_counts,
+        out_of_gamut: outside as f32 / (source.width() * source.height()).max(1) as f32,
+    }
+}
+
+fn srgb_to_linear(rgb: &Rgb<u8>) -> [f32; 3] {
+    let linear: LinSrgb = Srgb::new(rgb.0[0], rgb.0[1], rgb.0[2]).into_format::<f32>().into_linear();
+    [linear.red, linear.green, linear.blue]
+}
+
+fn linear_to_oklab(linear: &Rgb<f32>) -> Oklab {
+    LinSrgb::new(linear.0[0], linear.0[1], linear.0[2]).into_color()
+}
+
+/// Light mixes linearly, so blurring has to happen here rather than on the sRGB values.
+fn to_linear(image: &RgbImage) -> Rgb32FImage {
+    let mut linear = Rgb32FImage::new(image.width(), image.height());
+    linear
+        .par_pixels_mut()
+        .zip(image.par_pixels())
+        .for_each(|(linear, pixel)| *linear = Rgb(srgb_to_linear(pixel)));
+    linear
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// The faces of the convex hull of `points`, as unit normals pointing out and their distance
+/// from the origin. A panel has few enough inks that trying every triple is quick.
+fn hull_planes(points: &[[f32; 3]]) -> Vec<([f32; 3], f32)> {
+    let mut planes: Vec<([f32; 3], f32)> = Vec::new();
+    for (i, a) in points.iter().enumerate() {
+        for (j, b) in points.iter().enumerate().skip(i + 1) {
+            for c in &points[j + 1..] {
+                let ab = [0, 1, 2].map(|k| b[k] - a[k]);
+                let ac = [0, 1, 2].map(|k| c[k] - a[k]);
+                let normal = [
+                    ab[1] * ac[2] - ab[2] * ac[1],
+                    ab[2] * ac[0] - ab[0] * ac[2],
+                    ab[0] * ac[1] - ab[1] * ac[0],
+                ];
+                let length = dot(normal, normal).sqrt();
+                if length < f32::EPSILON {
+                    continue;
+                }
+                let normal = normal.map(|n| n / length);
+                let offset = dot(normal, *a);
+                // a face has every point on one side; if they're all on it, the inks are flat
+                // and both sides bound them
+                let sides = points.iter().map(|p| dot(normal, *p) - offset);
+                let below = sides.clone().all(|side| side <= GAMUT_TOLERANCE);
+                let above = sides.clone().all(|side| side >= -GAMUT_TOLERANCE);
+                if below {
+                    planes.push((normal, offset));
+                }
+                if above {
+                    planes.push((normal.map(|n| -n), -offset));
+                }
+            }
+        }
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::panel::SPECTRA_6_13IN3;
+
+    fn out_of_gamut(rgb: [u8; 3]) -> bool {
+        let image = RgbImage::from_pixel(2, 1, Rgb(rgb));
+        let frame = EpdFrame::new(2, 1, DisplayColor::White);
+        measure(&image, &image, &frame, &SPECTRA_6_13IN3.palette()).out_of_gamut > 0.0
+    }
+
+    #[test]
+    fn the_same_image_is_off_by_nothing() {
+        let image = RgbImage::from_fn(16, 16, |x, y| Rgb([x as u8 * 16, y as u8 * 16, 128]));
+        let frame = EpdFrame::new(16, 16, DisplayColor::White);
+        let report = measure(&image, &image, &frame, &SPECTRA_6_13IN3.palette());
+        assert_eq!((report.mean_delta_e, report.p95_delta_e), (0.0, 0.0));
+        assert_eq!(report.pixels(), 256);
+        assert!(report.ink_counts.contains(&(DisplayColor::White, 256)));
+    }
+
+    #[test]
+    fn inks_and_their_mixes_are_in_gamut() {
+        for (_, ink) in SPECTRA_6_13IN3.palette().inks() {
+            assert!(!out_of_gamut(ink.rgb.0), "{:?}", ink.rgb);
+        }
+        assert!(!out_of_gamut([128, 128, 128]));
+        assert!(!out_of_gamut([150, 80, 60]));
+    }
+
+    #[test]
+    fn colours_purer_than_the_inks_are_out_of_gamut() {
+        for rgb in [[255, 0, 0], [0, 255, 0], [0, 0, 255], [0, 255, 255], [255, 0, 255]] {
+            assert!(out_of_gamut(rgb), "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn hull_of_a_cube_is_its_six_faces() {
+        let corners: Vec<[f32; 3]> = (0..8).map(|i| [i & 1, i >> 1 & 1, i >> 2 & 1].map(|c| c as f32)).collect();
+        let mut planes = hull_planes(&corners);
+        planes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        planes.dedup();
+        assert_eq!(planes.len(), 6);
+        for (normal, offset) in planes {
+            assert_eq!(dot(normal, normal), 1.0);
+            assert!(offset == 0.0 || offset == 1.0);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Renders frames as they look on the wall rather than in the palette's ideal colours.
+
+use crate::color::ink_palette::InkPalette;
+use crate::frame::{EpdFrame, INVALID_NYBBLE_RGB};
+use image::imageops::fast_blur;
+use image::{ImageBuffer, Luma, Rgb, Rgb32FImage, RgbImage};
+use palette::{LinSrgb, Srgb};
+use rayon::prelude::*;
+
+/// Enough that single dots of ink soften into their neighbours the way they do from a step back.
+pub const DEFAULT_DOT_GAIN: f32 = 0.6;
+/// How much paper grain at full strength brightens or darkens the surface, as a share of the light.
+const TEXTURE_DEPTH: f32 = 0.08;
+/// Size of the grain's flecks, in pixels.
+const TEXTURE_SIGMA: f32 = 1.2;
+
+/// How to render a frame as it'll look. The palette does most of the work: calibrated inks, or a
+/// panel's typical ones, are far duller than the ideal colours and their white is a light grey.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Simulation {
+    /// How far, in pixels, ink spreads into neighbouring dots, counting the softening at
+    /// viewing distance.
+    pub dot_gain: f32,
+    /// Strength of the paper's grain, from 0 for none to 1.
+    pub texture: f32,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self {
+            dot_gain: DEFAULT_DOT_GAIN,
+            texture: 0.0,
+        }
+    }
+}
+
+impl Simulation {
+    /// `frame` in its native layout, each ink in `palette`'s colour. Nybbles that aren't inks
+    /// show as [`INVALID_NYBBLE_RGB`].
+    pub fn render(&self, frame: &EpdFrame, palette: &InkPalette) -> RgbImage {
+        let mut lights = [srgb_to_linear(INVALID_NYBBLE_RGB); 16];
+        for (display_color, ink) in palette.inks() {
+            lights[u8::from(display_color) as usize] = srgb_to_linear(ink.rgb);
+        }
+        let (width, height) = (frame.width(), frame.height());
+        let nybbles: Vec<u8> = frame.nybbles().collect();
+        let mut light = Rgb32FImage::new(width, height);
+        light
+            .par_pixels_mut()
+            .zip(nybbles.par_iter())
+            .for_each(|(pixel, &nybble)| *pixel = Rgb(lights[nybble as usize]));
+
+        // light spreads, so ink is blurred as light rather than as sRGB values
+        if self.dot_gain > 0.0 {
+            light = fast_blur(&light, self.dot_gain);
+        }
+        if self.texture > 0.0 {
+            let grain = grain(width, height);
+            let depth = self.texture.min(1.0) * TEXTURE_DEPTH;
+            light
+                .par_pixels_mut()
+                .zip(grain.par_pixels())
+                .for_each(|(pixel, grain)| *pixel = Rgb(pixel.0.map(|c| c * (1.0 + depth * grain.0[0]))));
+        }
+
+        let mut rgb = RgbImage::new(width, height);
+        rgb.par_pixels_mut()
+            .zip(light.par_pixels())
+            .for_each(|(pixel, light)| *pixel = linear_to_srgb(light.0));
+        rgb
+    }
+}
+
+fn srgb_to_linear(rgb: Rgb<u8>) -> [f32; 3] {
+    let linear: LinSrgb = Srgb::new(rgb.0[0], rgb.0[1], rgb.0[2]).into_format::<f32>().into_linear();
+    [linear.red, linear.green, linear.blue]
+}
+
+fn linear_to_srgb(linear: [f32; 3]) -> Rgb<u8> {
+    let [red, green, blue] = linear.map(|c| c.clamp(0.0, 1.0));
+    let srgb: Srgb<u8> = Srgb::from_linear(LinSrgb::new(red, green, blue));
+    Rgb([srgb.red, srgb.green, srgb.blue])
+}
+
+/// Fine, fixed noise with a standard deviation of about 1, the same for every frame so previews
+/// stay comparable.
+fn grain(width: u32, height: u32) -> ImageBuffer<Luma<f32>, Vec<f32>> {
+    let noise = ImageBuffer::from_fn(width, height, |x, y| {
+        let mut hash = x.wrapping_mul(0x9E37_79B1) ^ y.wrapping_mul(0x85EB_CA77);
+        hash ^= hash >> 15;
+        hash = hash.wrapping_mul(0x2C1B_3C6D);
+        hash ^= hash >> 12;
+        Luma([(hash & 0xFFFF) as f32 / 0xFFFF as f32 - 0.5])
+    });
+    let mut grain = fast_blur(&noise, TEXTURE_SIGMA);
+    let pixels = (width * height).max(1) as f32;
+    let deviation = (grain.pixels().map(|p| p.0[0] * p.0[0]).sum::<f32>() / pixels).sqrt();
+    if deviation > 0.0 {
+        grain.pixels_mut().for_each(|p| p.0[0] /= deviation);
+    }
+    grain
+}
@@ -0,0 +1,204 @@
+//! SVG input, rasterised straight at the panel's resolution so edges stay sharp.
+
+use crate::color::e_paper_color_map::EPaperColorMap;
+use crate::color::ink_palette::InkPalette;
+use crate::fit::{matte, FitMode, Matting};
+use crate::text::FONT;
+use flate2::read::GzDecoder;
+use image::error::{DecodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use image::{DynamicImage, GrayImage, ImageError, Limits, Luma, Rgb, RgbImage, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{Color, Group, Node, Opacity, Options, Paint, ShapeRendering, TextRendering, Tree};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+
+/// Family name of [`FONT`].
+const BUNDLED_FAMILY: &str = "DejaVu Sans";
+
+/// How much of a document is looked at to tell whether it's an SVG.
+const SNIFF_LEN: usize = 1024;
+
+/// Whether `bytes` look like an SVG (or gzipped SVGZ) document rather than a raster image.
+pub fn is_svg(bytes: &[u8]) -> bool {
+    if bytes.starts_with(&[0x1F, 0x8B]) {
+        // only the start is inflated; a stream cut short or corrupt further on still sniffs
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let _ = GzDecoder::new(bytes).take(SNIFF_LEN as u64).read_to_end(&mut head);
+        return looks_like_svg(&head);
+    }
+    looks_like_svg(&bytes[..bytes.len().min(SNIFF_LEN)])
+}
+
+fn looks_like_svg(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let head = head.trim_start_matches('\u{FEFF}').trim_start();
+    (head.starts_with("<?xml") || head.starts_with("<!") || head.starts_with("<svg")) && head.contains("<svg")
+}
+
+/// Parses an SVG, resolving relative image links against `resources_dir`. Text is set in the
+/// bundled font unless the system has the one asked for.
+pub(crate) fn parse(bytes: &[u8], resources_dir: Option<&Path>, crisp: bool) -> Result<Tree, ImageError> {
+    let mut options = Options {
+        resources_dir: resources_dir.map(Path::to_path_buf),
+        font_family: BUNDLED_FAMILY.to_string(),
+        ..Options::default()
+    };
+    if crisp {
+        options.shape_rendering = ShapeRendering::CrispEdges;
+        options.text_rendering = TextRendering::OptimizeSpeed;
+    }
+    let fontdb = options.fontdb_mut();
+    fontdb.load_system_fonts();
+    fontdb.load_font_data(FONT.to_vec());
+    fontdb.set_serif_family(BUNDLED_FAMILY);
+    fontdb.set_sans_serif_family(BUNDLED_FAMILY);
+    Tree::from_data(bytes, &options).map_err(|err| {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Name("SVG".to_string()), err))
+    })
+}
+
+/// The SVG at its own size, over white.
+pub(crate) fn rasterize(tree: &Tree) -> Result<RgbImage, ImageError> {
+    let size = tree.size().to_int_size();
+    let pixmap = page(size.width(), size.height())?;
+    let background = RgbImage::from_pixel(size.width(), size.height(), Rgb([255; 3]));
+    Ok(render(tree, pixmap, Transform::identity(), &background))
+}
+
+/// The SVG laid out on a `width`×`height` page the way `fit_mode` would lay out a raster image,
+/// with transparent areas showing the matting, or white.
+pub(crate) fn rasterize_fitted(
+    tree: &Tree,
+    width: u32,
+    height: u32,
+    fit_mode: FitMode,
+    palette: &InkPalette,
+) -> Result<RgbImage, ImageError> {
+    let pixmap = page(width, height)?;
+    let size = tree.size();
+    let (scale_x, scale_y) = (width as f32 / size.width(), height as f32 / size.height());
+    let (scale_x, scale_y) = match fit_mode {
+        FitMode::Fill => (scale_x.max(scale_y), scale_x.max(scale_y)),
+        FitMode::Contain(_) => (scale_x.min(scale_y), scale_x.min(scale_y)),
+        FitMode::Stretch => (scale_x, scale_y),
+        FitMode::Center(_) => (1.0, 1.0),
+    };
+    let transform = Transform::from_row(
+        scale_x,
+        0.0,
+        0.0,
+        scale_y,
+        (width as f32 - size.width() * scale_x) / 2.0,
+        (height as f32 - size.height() * scale_y) / 2.0,
+    );
+    let background = match fit_mode {
+        FitMode::Contain(matting) | FitMode::Center(matting) => {
+            let source = match matting {
+                Matting::Blur => DynamicImage::ImageRgb8(rasterize(tree)?),
+                Matting::Color(_) => DynamicImage::new_rgb8(1, 1),
+            };
+            matte(&source, width, height, matting, palette)
+        }
+        FitMode::Fill | FitMode::Stretch => RgbImage::from_pixel(width, height, Rgb([255; 3])),
+    };
+    Ok(render(tree, pixmap, transform, &background))
+}
+
+/// A blank page to render onto, or an error if it's empty or bigger than an image may be.
+fn page(width: u32, height: u32) -> Result<Pixmap, ImageError> {
+    let too_big = Limits::default()
+        .max_alloc
+        .is_some_and(|max_alloc| width as u64 * height as u64 * 4 > max_alloc);
+    match too_big {
+        true => None,
+        false => Pixmap::new(width, height),
+    }
+    .ok_or_else(|| ImageError::Limits(LimitError::from_kind(LimitErrorKind::DimensionError)))
+}
+
+fn render(tree: &Tree, mut pixmap: Pixmap, transform: Transform, background: &RgbImage) -> RgbImage {
+    let (width, height) = (pixmap.width(), pixmap.height());
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+    let rendered = RgbaImage::from_raw(width, height, pixmap.take()).expect("Pixmap is RGBA");
+    // premultiplied, so whatever is left of the background just adds on
+    RgbImage::from_fn(width, height, |x, y| {
+        let [r, g, b, a] = rendered.get_pixel(x, y).0;
+        let behind = background.get_pixel(x, y).0;
+        let under = |channel: u8, behind: u8| channel.saturating_add(((behind as u16 * (255 - a) as u16) / 255) as u8);
+        Rgb([under(r, behind[0]), under(g, behind[1]), under(b, behind[2])])
+    })
+}
+
+/// Every solid, opaque colour the SVG fills or strokes with.
+fn flat_colors(group: &Group, colors: &mut HashSet<Rgb<u8>>) {
+    for node in group.children() {
+        match node {
+            Node::Group(group) => flat_colors(group, colors),
+            Node::Path(path) => {
+                let paints = [
+                    path.fill().map(|fill| (fill.paint(), fill.opacity())),
+                    path.stroke().map(|stroke| (stroke.paint(), stroke.opacity())),
+                ];
+                for (paint, opacity) in paints.into_iter().flatten() {
+                    if let Paint::Color(Color { red, green, blue }) = paint
+                        && opacity == Opacity::ONE
+                    {
+                        colors.insert(Rgb([*red, *green, *blue]));
+                    }
+                }
+            }
+            Node::Text(text) => flat_colors(text.flattened(), colors),
+            Node::Image(_) => {}
+        }
+    }
+}
+
+/// Snaps every pixel of `img` painted in one of the SVG's flat colours to the nearest ink, and
+/// returns a mask of them so dithering can leave them alone.
+pub(crate) fn snap_flat_fills(img: &mut RgbImage, tree: &Tree, color_map: &EPaperColorMap) -> GrayImage {
+    let mut colors = HashSet::new();
+    flat_colors(tree.root(), &mut colors);
+    let snapped: HashMap<Rgb<u8>, Rgb<u8>> = colors
+        .into_iter()
+        .map(|rgb| (rgb, color_map.palette().rgb(color_map.nearest_rgb(rgb))))
+        .collect();
+    let mut mask = GrayImage::new(img.width(), img.height());
+    for (pixel, masked) in img.pixels_mut().zip(mask.pixels_mut()) {
+        if let Some(ink) = snapped.get(pixel) {
+            *pixel = *ink;
+            *masked = Luma([255]);
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzipped(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn sniffs_svg_and_svgz() {
+        let svg = br#"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg"/>"#;
+        assert!(is_svg(svg));
+        assert!(is_svg(b"\xEF\xBB\xBF  <svg/>"));
+        assert!(is_svg(&gzipped(svg)));
+    }
+
+    #[test]
+    fn other_gzip_is_not_svg() {
+        assert!(!is_svg(&gzipped(b"not an svg at all")));
+        assert!(!is_svg(&gzipped(b"<?xml version=\"1.0\"?><feed/>")));
+        assert!(!is_svg(&[0x1F, 0x8B, 0x08, 0x00]));
+        assert!(!is_svg(b"\x89PNG\r\n\x1A\n"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Text drawn with the bundled font, for overlays and dashboards.
+
+use ab_glyph::{point, Font, FontRef, PxScale, PxScaleFont, ScaleFont};
+use image::{Rgb, RgbImage};
+
+/// DejaVu Sans Bold, see `fonts/LICENSE-DejaVu.txt`.
+pub(crate) static FONT: &[u8] = include_bytes!("../fonts/DejaVuSans-Bold.ttf");
+
+/// The bundled font at `size` pixels per line.
+pub(crate) struct Text {
+    font: PxScaleFont<FontRef<'static>>,
+}
+
+impl Text {
+    pub(crate) fn new(size: f32) -> Self {
+        let font = FontRef::try_from_slice(FONT).expect("Bundled font parses");
+        Self {
+            font: font.into_scaled(PxScale::from(size)),
+        }
+    }
+
+    pub(crate) fn line_height(&self) -> u32 {
+        (self.font.height() + self.font.line_gap()).ceil() as u32
+    }
+
+    pub(crate) fn width(&self, line: &str) -> u32 {
+        let mut width = 0.0;
+        let mut previous = None;
+        for c in line.chars() {
+            let id = self.font.glyph_id(c);
+            if let Some(previous) = previous {
+                width += self.font.kern(previous, id);
+            }
+            width += self.font.h_advance(id);
+            previous = Some(id);
+        }
+        width.ceil() as u32
+    }
+
+    /// Draws one line with its top-left corner at `left`, `top`, clipped to `img`. Glyph pixels
+    /// are either fully in or out, so the text only uses `rgb` and stays sharp on the panel.
+    pub(crate) fn draw(&self, img: &mut RgbImage, line: &str, left: i32, top: i32, rgb: Rgb<u8>) {
+        let mut caret = point(left as f32, top as f32 + self.font.ascent());
+        let mut previous = None;
+        for c in line.chars() {
+            let id = self.font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret.x += self.font.kern(previous, id);
+            }
+            let glyph = id.with_scale_and_position(self.font.scale(), caret);
+            caret.x += self.font.h_advance(id);
+            previous = Some(id);
+            let Some(outline) = self.font.outline_glyph(glyph) else {
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|x, y, coverage| {
+                let x = bounds.min.x as i32 + x as i32;
+                let y = bounds.min.y as i32 + y as i32;
+                if coverage >= 0.5 && x >= 0 && y >= 0 && (x as u32) < img.width() && (y as u32) < img.height() {
+                    img.put_pixel(x as u32, y as u32, rgb);
+                }
+            });
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use eink_convert::color::display_color::DisplayColor;
+use eink_convert::frame::{EpdFrame, FrameError, PackedRows};
+use eink_convert::panel::{Controller, ControllerSplit, InitCommand, PanelProfile};
 use embedded_hal::delay::DelayNs;
-use crate::display_constants::{EPD_BYTES_TOTAL, EPD_BYTE_WIDTH_PER_CHIP};
 use crate::e_paper_display_driver::{command_code::CommandCode, gpio_pin::GpioPin};
 use rppal::gpio::Level::{High, Low};
 use rppal::gpio::{Error as GpioError, Gpio, InputPin, OutputPin};
@@ -17,6 +19,10 @@ pub enum EpdError {
     Io(#[from] IoError),
     #[error(transparent)]
     Gpio(#[from] GpioError),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error("Frame is {0}x{1}, but the {2} panel is {3}x{4}")]
+    FrameSize(u32, u32, &'static str, u32, u32),
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -27,6 +33,16 @@ enum SelectedChip {
     Neither,
 }
 
+impl From<Controller> for SelectedChip {
+    fn from(controller: Controller) -> Self {
+        match controller {
+            Controller::Main => SelectedChip::Main,
+            Controller::Peri => SelectedChip::Peri,
+            Controller::Both => SelectedChip::Both,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct EPaperDisplayBBDriver {
     data_pin: OutputPin,
@@ -38,11 +54,12 @@ pub struct EPaperDisplayBBDriver {
     busy_pin: InputPin,
     power_pin: OutputPin,
     selected_chip: SelectedChip,
-    delay: Delay
+    delay: Delay,
+    panel: &'static PanelProfile,
 }
 
 impl EPaperDisplayBBDriver {
-    pub fn new() -> Result<EPaperDisplayBBDriver, EpdError> {
+    pub fn new(panel: &'static PanelProfile) -> Result<EPaperDisplayBBDriver, EpdError> {
         let gpio = Gpio::new()?;
 
         let mut clock_pin = gpio.get(GpioPin::SerialClockPin as u8)?.into_output();
@@ -80,31 +97,14 @@ impl EPaperDisplayBBDriver {
             power_pin,
             selected_chip: SelectedChip::Both,
             delay: Delay::new(),
+            panel,
         };
 
         this.reset();
         this.wait_for_not_busy();
 
-        let boot_sequence = [
-            (CommandCode::AnTm, SelectedChip::Main),
-            (CommandCode::Cmd66, SelectedChip::Both),
-            (CommandCode::Psr, SelectedChip::Both),
-            (CommandCode::Cdi, SelectedChip::Both),
-            (CommandCode::Tcon, SelectedChip::Both),
-            (CommandCode::Agid, SelectedChip::Both),
-            (CommandCode::Pws, SelectedChip::Both),
-            (CommandCode::Ccset, SelectedChip::Both),
-            (CommandCode::Tres, SelectedChip::Both),
-            (CommandCode::Pwr, SelectedChip::Main),
-            (CommandCode::EnBuf, SelectedChip::Main),
-            (CommandCode::BtstP, SelectedChip::Main),
-            (CommandCode::BoostVddpEn, SelectedChip::Main),
-            (CommandCode::BtstN, SelectedChip::Main),
-            (CommandCode::BuckBoostVddn, SelectedChip::Main),
-            (CommandCode::TftVcomPower, SelectedChip::Main),
-        ];
-        for (command, chip) in boot_sequence {
-            this.send_command(command, chip);
+        for command in panel.init {
+            this.send_init_command(command);
         }
 
         Ok(this)
@@ -163,6 +163,22 @@ impl EPaperDisplayBBDriver {
         self.select_chip(SelectedChip::Neither);
     }
 
+    /// The chips `controller` stands for on this panel; one with a single controller has no
+    /// peri chip to send to.
+    fn chips(&self, controller: Controller) -> SelectedChip {
+        match (controller, self.panel.split) {
+            (Controller::Both, ControllerSplit::Single) => SelectedChip::Main,
+            (controller, _) => controller.into(),
+        }
+    }
+
+    fn send_init_command(&mut self, init: &InitCommand) {
+        self.select_chip(self.chips(init.controller));
+        self.spi_write(&[init.command]);
+        self.spi_write(init.data);
+        self.select_chip(SelectedChip::Neither);
+    }
+
     fn wait_for_not_busy(&self) {
         sleep(Duration::from_millis(50));
         info!("waiting for not busy");
@@ -174,23 +190,23 @@ impl EPaperDisplayBBDriver {
 
     fn turn_display_on(&mut self) {
         info!("Write PON");
-        self.send_command(CommandCode::PowerOn, SelectedChip::Both);
+        self.send_command(CommandCode::PowerOn, self.chips(Controller::Both));
         self.wait_for_not_busy();
 
         sleep(Duration::from_millis(50));
 
         info!("Write DRF");
-        self.send_command(CommandCode::Drf, SelectedChip::Both);
+        self.send_command(CommandCode::Drf, self.chips(Controller::Both));
         self.wait_for_not_busy();
 
         info!("Write POF");
-        self.send_command(CommandCode::Pof, SelectedChip::Both);
+        self.send_command(CommandCode::Pof, self.chips(Controller::Both));
 
         info!("Display On");
     }
 
     pub fn sleep(&mut self) {
-        self.send_command(CommandCode::DeepSleep, SelectedChip::Both);
+        self.send_command(CommandCode::DeepSleep, self.chips(Controller::Both));
         sleep(Duration::from_secs(2));
     }
 
@@ -204,32 +220,44 @@ impl EPaperDisplayBBDriver {
 }
 
 impl EPaperDisplayBBDriver {
-    pub fn clear(&mut self) {
-        let nybble_ones: &[u8; EPD_BYTES_TOTAL] = &[0b00010001u8; EPD_BYTES_TOTAL];
-        self.display(nybble_ones);
+    pub fn clear(&mut self) -> Result<(), EpdError> {
+        self.display(&EpdFrame::new(self.panel.width, self.panel.height, DisplayColor::White))
     }
 
-    pub fn display(&mut self, image: &[u8]) {
-        assert_eq!(image.len(), EPD_BYTES_TOTAL);
-        self.select_chip(SelectedChip::Main);
-        self.spi_write(&[CommandCode::Dtm.cmd()]);
-        for (i, chunk) in image.chunks(EPD_BYTE_WIDTH_PER_CHIP).enumerate() {
-            if i % 2 == 0 {
-                self.spi_write(chunk);
-            }
+    /// Rows are translated to the controller's colour codes on the way out. A panel split over
+    /// two chips has each chip take its whole half of the frame in one go, so `frame` is read
+    /// through twice rather than buffering the other half.
+    pub fn display(&mut self, frame: &impl PackedRows) -> Result<(), EpdError> {
+        let panel = self.panel;
+        if (frame.width(), frame.height()) != (panel.width, panel.height) {
+            return Err(EpdError::FrameSize(frame.width(), frame.height(), panel.name, panel.width, panel.height));
         }
-        self.select_chip(SelectedChip::Neither);
-        self.select_chip(SelectedChip::Peri);
-        self.spi_write(&[CommandCode::Dtm.cmd()]);
-        for (i, chunk) in image.chunks(EPD_BYTE_WIDTH_PER_CHIP).enumerate() {
-            if i % 2 == 1 {
-                self.spi_write(chunk);
-            }
+        let wire_bytes = panel.wire_bytes();
+        let row_len = (panel.width / 2) as usize;
+        let mut wire_row = vec![0u8; row_len];
+        let passes = match panel.split {
+            ControllerSplit::Single => vec![(SelectedChip::Main, 0..row_len)],
+            // each chip drives one half of every row
+            ControllerSplit::HalfRows => vec![
+                (SelectedChip::Main, 0..row_len / 2),
+                (SelectedChip::Peri, row_len / 2..row_len),
+            ],
+        };
+        for (chip, bytes) in passes {
+            self.select_chip(chip);
+            self.spi_write(&[CommandCode::Dtm.cmd()]);
+            frame.for_each_row(&mut |row| {
+                for (wire, byte) in wire_row[bytes.clone()].iter_mut().zip(&row[bytes.clone()]) {
+                    *wire = wire_bytes[*byte as usize];
+                }
+                self.spi_write(&wire_row[bytes.clone()]);
+            })?;
+            self.select_chip(SelectedChip::Neither);
         }
-        self.select_chip(SelectedChip::Neither);
         sleep(Duration::from_millis(100));
 
         self.turn_display_on();
+        Ok(())
     }
 }
 
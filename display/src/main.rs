@@ -1,8 +1,9 @@
-mod display_constants;
 mod e_paper_display_driver;
 
 use clap::Parser;
 use e_paper_display_driver::bit_bang_driver::EPaperDisplayBBDriver as Driver;
+use eink_convert::panel::PanelProfile;
+use eink_convert::view_frame;
 use std::error::Error;
 use std::fs;
 use std::path::PathBuf;
@@ -14,6 +15,9 @@ use tracing::metadata::LevelFilter;
 #[derive(Debug, Parser)]
 struct Args {
     file: Option<PathBuf>,
+    /// Panel wired to the Pi: 13in3-e6 or 7in3-acep
+    #[clap(long, value_parser = PanelProfile::by_name, default_value_t = <&PanelProfile>::default())]
+    panel: &'static PanelProfile,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -21,22 +25,23 @@ fn main() -> Result<(), Box<dyn Error>> {
         .with_max_level(LevelFilter::INFO)
         .init();
     let args = Args::parse();
+    // a bad file is caught before the panel is powered up
     info!("Reading file...");
-    info!("File loaded. Init driver.");
-    let mut device = Driver::new()?;
+    let bytes = args.file.map(fs::read).transpose()?;
+    let epd_image = bytes.as_deref().map(|bytes| view_frame(bytes, args.panel)).transpose()?;
+    info!("Init driver.");
+    let mut device = Driver::new(args.panel)?;
 
     info!("Device init");
-    if let Some(file) = args.file {
-        let epd_image = fs::read(file)?;
-
-        info!("Cleared. Sending image...");
-        device.display(&epd_image);
+    if let Some(epd_image) = epd_image {
+        info!("File loaded. Sending image...");
+        device.display(&epd_image)?;
         info!("Image sent. Sleeping display...");
         device.sleep();
     }
     else {
         info!("Clearing display");
-        device.clear();
+        device.clear()?;
     }
     info!("Screen clear. Waiting 2s...");
     sleep(Duration::from_secs(2));
@@ -1,22 +1,29 @@
 use actix_files::NamedFile;
-use actix_multipart::form::{json::Json as MpJson, tempfile::TempFile, MultipartForm};
-use actix_web::error::{ErrorBadRequest, ErrorNotFound, ErrorUnauthorized};
+use actix_multipart::form::{bytes::Bytes, json::Json as MpJson, MultipartForm, MultipartFormConfig};
+use actix_web::error::{ErrorBadRequest, ErrorNotFound, ErrorUnauthorized, ErrorUnsupportedMediaType};
 use actix_web::web::Path;
 use actix_web::{
     get, middleware::Logger, post, App, HttpResponse, HttpServer, Responder, Result as ActixResult,
 };
 use actix_web_httpauth::middleware::HttpAuthentication;
-use eink_convert::convert;
+use eink_convert::adjust::Adjustments;
+use eink_convert::container::Compression;
+use eink_convert::fit::FitMode;
+use eink_convert::formats::check_supported;
+use eink_convert::orientation::{as_hung, Mounting, Orientation};
+use eink_convert::overlay::{Overlay, OverlayText};
+use eink_convert::panel::PanelProfile;
+use eink_convert::simulation::Simulation;
+use eink_convert::{decode_image, source_metadata, svg, Converter};
 use image::imageops::Lanczos3;
-use image::metadata::Orientation::NoTransforms;
+use image::DynamicImage;
 use image::ImageFormat::Jpeg;
-use image::{DynamicImage, ImageDecoder, ImageReader};
 use log::{error, info};
 use serde::Deserialize;
 use serde_repr::Deserialize_repr;
 use std::env::var;
 use std::path::PathBuf;
-use tokio::fs::remove_file;
+use tokio::fs::{remove_file, write};
 use tokio::process::Command;
 use tokio::spawn;
 
@@ -28,10 +35,35 @@ enum ImageConversionError {
     ImageError(#[from] image::ImageError),
 }
 
+/// Uploads are converted straight from memory, so they must fit in it.
+const UPLOAD_LIMIT: usize = 50 * 1024 * 1024;
+
 fn nybble_img_bin_path(day: u8, hour: u8) -> PathBuf {
     PathBuf::from("./nybble_images").join(format!("{}/{}.bin", day, hour))
 }
 
+/// How the frame hangs, from `FRAME_MOUNTING`; landscape when unset.
+fn frame_mounting() -> Mounting {
+    match var("FRAME_MOUNTING") {
+        Ok(mounting) => mounting.parse().unwrap_or_else(|e| {
+            error!("{}, assuming {}", e, Mounting::default());
+            Mounting::default()
+        }),
+        Err(_) => Mounting::default(),
+    }
+}
+
+/// Which panel the frame has, from `FRAME_PANEL`; the 13.3" E6 when unset.
+fn frame_panel() -> &'static PanelProfile {
+    match var("FRAME_PANEL") {
+        Ok(panel) => PanelProfile::by_name(&panel).unwrap_or_else(|e| {
+            error!("{}, assuming {}", e, <&PanelProfile>::default());
+            Default::default()
+        }),
+        Err(_) => Default::default(),
+    }
+}
+
 fn thumb_path(day: u8, hour: u8) -> PathBuf {
     PathBuf::from("./thumbs").join(format!("{}/{}.jpeg", day, hour))
 }
@@ -82,13 +114,26 @@ impl Into<u8> for ValidDay {
 
 #[derive(Debug, Deserialize)]
 struct UploadJsonForm {
-    show_now: bool
+    show_now: bool,
+    #[serde(default)]
+    fit: FitMode,
+    #[serde(default)]
+    orientation: Orientation,
+    /// Exposure, contrast and the like, e.g. `{"exposure": 0.5, "saturation": 1.2}`.
+    #[serde(default)]
+    adjustments: Adjustments,
+    /// Printed on the picture, e.g. who's in it.
+    #[serde(default)]
+    caption: String,
+    /// Print the date the photo was taken.
+    #[serde(default)]
+    show_date: bool,
 }
 
 #[derive(Debug, MultipartForm)]
 struct UploadMultipartForm {
     #[multipart()]
-    file: TempFile,
+    file: Bytes,
     json: MpJson<UploadJsonForm>,
 }
 
@@ -102,7 +147,12 @@ async fn pico() -> impl Responder {
     HttpResponse::Ok().body(include_str!("../static/css/pico.classless.min.css"))
 }
 
-async fn save_image(day: u8, hour: u8, file: &TempFile) -> Result<(), ImageConversionError> {
+async fn save_image(
+    day: u8,
+    hour: u8,
+    file: &Bytes,
+    converter: &Converter,
+) -> Result<(), ImageConversionError> {
     let bin_path = nybble_img_bin_path(day, hour);
     let remove_bin = remove_file(&bin_path).await;
     if let Err(remove_bin) = remove_bin {
@@ -119,19 +169,27 @@ async fn save_image(day: u8, hour: u8, file: &TempFile) -> Result<(), ImageConve
         // continue anyhow
     }
 
-    let mut decoder = ImageReader::open(&file.file.path())?
-        .with_guessed_format()?
-        .into_decoder()?;
-    let orientation = decoder.orientation().unwrap_or(NoTransforms);
-    let mut img = DynamicImage::from_decoder(decoder)?;
-    img.apply_orientation(orientation);
-    let resized = img.resize(256, 256, Lanczos3);
-    if resized.save_with_format(&thumb_path, Jpeg).is_err() {
-        error!("Could not save a thumbnail");
+    let mut conversion = match svg::is_svg(&file.data) {
+        // rasterised afresh at the panel's resolution rather than scaled up from its own size
+        true => converter.convert_svg(&file.data, None)?,
+        false => {
+            let img = decode_image(&file.data)?;
+            let source = source_metadata(&img, &file.data);
+            converter.convert_source(img, source)
+        }
+    };
+    conversion.source.file_name = file.file_name.clone();
+    // the thumbnail shows the frame as it'll look on the wall
+    if let Some(preview) = conversion.preview.take() {
+        let options = converter.options();
+        let hung = DynamicImage::ImageRgb8(as_hung(&preview, options.mounting, options.panel));
+        if hung.resize(256, 256, Lanczos3).save_with_format(&thumb_path, Jpeg).is_err() {
+            error!("Could not save a thumbnail");
+        }
     }
-    let binary_conversion = convert(&file.file.path(), &bin_path, None);
-    if let Err(err) = binary_conversion {
-        error!("Failed to convert file to binary: {}", err);
+    if let Err(err) = write(&bin_path, conversion.into_frame_file().to_bytes(Compression::Deflate)).await {
+        error!("Failed to write frame: {}", err);
+        return Err(err.into());
     }
     Ok(())
 }
@@ -142,11 +200,34 @@ async fn upload(
     MultipartForm(form): MultipartForm<UploadMultipartForm>,
 ) -> ActixResult<impl Responder> {
     let (day, hour) = path_parts.into_inner();
+    // caught here, as nobody's waiting on the conversion to hear about it
+    check_supported(&form.file.data).map_err(ErrorUnsupportedMediaType)?;
     let display_now = form.json.show_now;
+    let panel = frame_panel();
+    let mut converter = Converter::new()
+        .panel(panel)
+        .fit(form.json.fit)
+        .orientation(form.json.orientation)
+        .adjustments(form.json.adjustments)
+        .mounting(frame_mounting())
+        .simulation(Simulation::default())
+        .preview(true);
+    if form.json.show_date {
+        converter = converter.overlay(Overlay::new(OverlayText::CaptureDate));
+    }
+    let caption = form.json.caption.trim();
+    if !caption.is_empty() {
+        converter = converter.overlay(Overlay::new(OverlayText::Custom(caption.to_string())));
+    }
     spawn(async move {
-        if save_image(day.into(), hour.into(), &form.file).await.is_ok() && display_now {
+        let (day, hour) = (day.into(), hour.into());
+        let saved = save_image(day, hour, &form.file, &converter).await;
+        if let Err(err) = &saved {
+            error!("Could not convert {}/{}: {}", day, hour, err);
+        }
+        if saved.is_ok() && display_now {
             let mut display_cmd = Command::new("/usr/local/bin/eink-display");
-            display_cmd.args([nybble_img_bin_path(day.into(), hour.into())]);
+            display_cmd.arg(nybble_img_bin_path(day, hour)).arg("--panel").arg(panel.name);
             if let Err(e) = display_cmd.spawn() {
                 error!("Failed to spawn eink display: {}", e);
             }
@@ -163,7 +244,7 @@ async fn show(
     let (day, hour) = path_parts.into_inner();
     spawn(async move {
         let mut display_cmd = Command::new("/usr/local/bin/eink-display");
-        display_cmd.args([nybble_img_bin_path(day.into(), hour.into())]);
+        display_cmd.arg(nybble_img_bin_path(day.into(), hour.into())).arg("--panel").arg(frame_panel().name);
         if let Err(e) = display_cmd.spawn() {
             error!("Failed to spawn eink display: {}", e);
         }
@@ -202,6 +283,7 @@ async fn main() -> std::io::Result<()> {
             Err((ErrorUnauthorized("Not Authorized"), req))
         });
         App::new()
+            .app_data(MultipartFormConfig::default().memory_limit(UPLOAD_LIMIT).total_limit(UPLOAD_LIMIT))
             .wrap(Logger::default())
             .wrap(auth)
             .service(upload)